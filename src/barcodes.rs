@@ -1,7 +1,11 @@
 use anyhow::Result;
-use disambiseq::Disambibyte;
 use hashbrown::{HashMap, HashSet};
+use log::{info, warn};
+
+use crate::packed::{encode, PackedMap, PackedWindows, MAX_PACKED_LEN};
+use crate::whitelist::{self, Entry as WhitelistEntry, Format};
 use std::{
+    cmp::Reverse,
     fs::File,
     io::{BufRead, BufReader},
 };
@@ -9,173 +13,793 @@ use std::{
 type BarcodeID = usize;
 type EndPos = usize;
 
+const NUCLEOTIDES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+/// How a lookup key differs from the whitelist entry it maps to.
+/// Ordered from most to least trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Correction {
+    Exact,
+    /// Number of substituted nucleotides
+    Substitution(u8),
+    Indel,
+    /// Ambiguous substitution resolved by the base qualities
+    Resolved,
+    /// N bases matched as wildcards
+    Wildcard,
+}
+
+/// A barcode found in a sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarcodeMatch {
+    /// Position of the first nucleotide of the barcode
+    pub start: usize,
+    /// Position of the first nucleotide after the barcode
+    pub end: EndPos,
+    pub id: BarcodeID,
+    pub correction: Correction,
+    /// Number of substitutions within the spacer
+    pub spacer_errors: u8,
+    /// A different barcode matched equally well at another placement
+    pub ambiguous: bool,
+}
+
+impl BarcodeMatch {
+    /// Returns the quality of a match, lower is better
+    fn score(&self) -> (Correction, u8) {
+        (self.correction, self.spacer_errors)
+    }
+
+    /// Returns true if errors outside of the spacer were corrected
+    pub fn barcode_corrected(&self) -> bool {
+        match self.correction {
+            Correction::Exact => false,
+            Correction::Substitution(dist) => dist > self.spacer_errors,
+            _ => true,
+        }
+    }
+}
+
+/// A lookup key's barcode and how it was derived
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    id: BarcodeID,
+    correction: Correction,
+    spacer_errors: u8,
+}
+impl Entry {
+    fn new(id: BarcodeID, correction: Correction) -> Self {
+        Self {
+            id,
+            correction,
+            spacer_errors: 0,
+        }
+    }
+}
+
+/// A whitelist entry with one of its spacers
+#[derive(Debug)]
+struct Parent {
+    seq: Vec<u8>,
+    id: BarcodeID,
+    /// Length of the barcode without spacer
+    barcode_len: usize,
+}
+
+/// The errors that are corrected when building the barcode lookup
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tolerance {
+    /// Maximum number of substitutions (0 = exact matching)
+    pub mismatches: usize,
+    /// Maximum number of substitutions in the spacer,
+    /// if not set the spacer shares the budget of the barcode
+    pub spacer_mismatches: Option<usize>,
+    /// Also accept a single insertion or deletion (edit distance 1)
+    pub indels: bool,
+    /// Resolve ambiguous substitutions by base qualities if the most likely
+    /// barcode reaches this posterior probability
+    pub min_posterior: Option<f64>,
+    /// Maximum number of N bases treated as wildcards (0 = N is a mismatch)
+    pub max_n: usize,
+}
+impl Tolerance {
+    pub fn new(exact: bool, indels: bool) -> Self {
+        Self {
+            mismatches: if exact { 0 } else { 1 },
+            spacer_mismatches: None,
+            indels,
+            min_posterior: None,
+            max_n: 0,
+        }
+    }
+
+    /// Returns the tolerance with round specific mismatch budgets.
+    /// Exact matching can not be relaxed by a round
+    pub fn for_round(&self, mismatches: Option<usize>, spacer_mismatches: Option<usize>) -> Self {
+        if self.mismatches == 0 {
+            return Self {
+                spacer_mismatches: spacer_mismatches.map(|_| 0),
+                ..*self
+            };
+        }
+        Self {
+            mismatches: mismatches.unwrap_or(self.mismatches),
+            spacer_mismatches: spacer_mismatches.or(self.spacer_mismatches),
+            ..*self
+        }
+    }
+
+    /// Returns true if any substitution is corrected
+    fn corrects(&self) -> bool {
+        self.mismatches > 0 || self.spacer_mismatches.unwrap_or(0) > 0
+    }
+}
+
 #[derive(Debug)]
 pub struct Barcodes {
-    map: HashMap<Vec<u8>, usize>,
+    /// Lookup keys (whitelist entries and their neighbours) packed with 2 bits per nucleotide
+    map: PackedMap<Entry>,
     index: HashMap<usize, Vec<u8>>,
-    len: usize,
+    /// Names of the whitelist entries (e.g. wells) given in a second column
+    names: HashMap<usize, String>,
+    /// All barcode lengths present in the whitelist, longest first
+    lengths: Vec<usize>,
+    /// All lengths of the lookup keys (including indel neighbours), longest first
+    key_lengths: Vec<usize>,
     spacer_len: Option<usize>,
+    mismatches: usize,
+    spacer_mismatches: Option<usize>,
+    indels: bool,
+    /// Whitelist entries (with spacer)
+    parents: Vec<Parent>,
+    /// Neighbours shared by different barcodes and the indices of their parents
+    ambiguous: HashMap<Vec<u8>, Vec<usize>>,
+    min_posterior: Option<f64>,
+    max_n: usize,
 }
 impl Barcodes {
+    #[allow(dead_code)]
     pub fn from_file(path: &str, exact: bool) -> Result<Self> {
         let reader = File::open(path).map(BufReader::new)?;
         Self::from_buffer(reader, exact)
     }
-    pub fn from_file_with_spacer(path: &str, spacers: &Vec<Spacer>, exact: bool) -> Result<Self> {
+    #[allow(dead_code)]
+    pub fn from_file_with_spacer(path: &str, spacers: &[Spacer], exact: bool) -> Result<Self> {
         let reader = File::open(path).map(BufReader::new)?;
         Self::from_buffer_with_spacer(reader, spacers, exact)
     }
+    #[allow(dead_code)]
+    pub fn from_file_with_tolerance(
+        path: &str,
+        spacers: &[Spacer],
+        tolerance: &Tolerance,
+    ) -> Result<Self> {
+        let reader = File::open(path).map(BufReader::new)?;
+        Self::parse_buffer(reader, spacers, tolerance)
+    }
 
+    #[allow(dead_code)]
     pub fn from_buffer<R: BufRead>(reader: R, exact: bool) -> Result<Self> {
-        Self::parse_buffer(reader, &Vec::new(), exact)
+        Self::parse_buffer(reader, &[], &Tolerance::new(exact, false))
     }
 
+    #[allow(dead_code)]
     pub fn from_buffer_with_spacer<R: BufRead>(
         reader: R,
-        spacers: &Vec<Spacer>,
+        spacers: &[Spacer],
         exact: bool,
     ) -> Result<Self> {
-        Self::parse_buffer(reader, spacers, exact)
+        Self::parse_buffer(reader, spacers, &Tolerance::new(exact, false))
     }
 
     /// Parses a buffer and returns a Barcodes object
-    /// If a spacer is given, it is appended to each barcode
+    /// If a spacer is given, it is appended to each barcode.
+    /// Entries may have different lengths (e.g. staggered primers)
+    /// and may be followed by a tab separated name.
+    /// Blank lines and `#` comments are skipped
     pub fn parse_buffer<R: BufRead>(
         reader: R,
-        spacers: &Vec<Spacer>,
-        exact: bool,
+        spacers: &[Spacer],
+        tolerance: &Tolerance,
     ) -> Result<Self> {
+        let entries = whitelist::read_entries(reader, &Format::default())?;
+        Self::from_entries(&entries, spacers, tolerance)
+    }
+
+    /// Builds the lookup from the entries of a whitelist, indexed in their order
+    pub fn from_entries(entries: &[WhitelistEntry], spacers: &[Spacer], tolerance: &Tolerance) -> Result<Self> {
         let mut map = HashMap::new();
         let mut index = HashMap::new();
+        let mut names = HashMap::new();
         let mut sizes = HashSet::new();
+        let mut parents = Vec::new();
+        let mut duplicates = Vec::new();
 
         let first_spacer = spacers.first();
 
-        for (idx, line) in reader.lines().enumerate() {
-            let line = line?; 
-            if spacers.len() == 0 {
-                let barcode = Self::read_sequence(&line, None);
-                //println!("Line number: {}, length: {}, Barcode: {:?}", idx + 1, barcode.len(), barcode);
-
+        for (idx, entry) in entries.iter().enumerate() {
+            // reads are matched case-sensitively
+            if !entry.seq.bytes().all(|nuc| b"ACGT".contains(&nuc)) {
+                anyhow::bail!(
+                    "Whitelist entry {} on line {} has characters other than A, C, G, T (in upper case)",
+                    entry.seq, entry.line
+                );
+            }
+            if let Some(name) = &entry.name {
+                names.insert(idx, name.clone());
+            }
+            let seq = entry.seq.as_str();
+            if spacers.is_empty() {
+                let barcode = Self::read_sequence(seq, None);
                 sizes.insert(barcode.len());
-                map.entry(barcode.clone()).or_insert(idx);
+                if !map.contains_key(&barcode) {
+                    map.insert(barcode.clone(), Entry::new(idx, Correction::Exact));
+                    parents.push(Parent { seq: barcode.clone(), id: idx, barcode_len: barcode.len() });
+                } else {
+                    duplicates.push(entry.line);
+                }
                 index.entry(idx).or_insert(barcode);
             } else {
                 for spacer in spacers {
-                    let barcode = Self::read_sequence(&line, Some(spacer));
-                    //println!("Line number: {}, length: {}, Barcode: {:?}", idx + 1, barcode.len(), barcode);
-
+                    let barcode = Self::read_sequence(seq, Some(spacer));
                     sizes.insert(barcode.len());
-                    map.entry(barcode.clone()).or_insert(idx);
+                    match map.get(&barcode) {
+                        None => {
+                            map.insert(barcode.clone(), Entry::new(idx, Correction::Exact));
+                            let barcode_len = barcode.len() - spacer.seq().len();
+                            parents.push(Parent { seq: barcode.clone(), id: idx, barcode_len });
+                        }
+                        Some(existing) if existing.id != idx && duplicates.last() != Some(&entry.line) => {
+                            duplicates.push(entry.line);
+                        }
+                        Some(_) => {}
+                    }
                     index.entry(idx).or_insert(barcode);
                 }
             }
         }
 
-        if !exact {
-            let parent_barcodes = map.keys().cloned().collect::<Vec<_>>();
-            let dsb = Disambibyte::from_slice(&parent_barcodes);
-            dsb.unambiguous().iter().for_each(|(child, parent)| {
-                map.insert(
-                    child.sequence().to_owned(),
-                    *map.get(parent.sequence()).unwrap(),
-                );
-            });
+        if sizes.is_empty() {
+            anyhow::bail!("No barcodes found");
+        }
+        let max_len = sizes.iter().max().copied().unwrap_or(0);
+        if tolerance.corrects() && tolerance.indels && max_len + 1 > MAX_PACKED_LEN {
+            anyhow::bail!(
+                "Barcodes of {} nt (including the spacer) can't be corrected for indels: \
+                an insertion gives {} nt, but up to {} nt are supported",
+                max_len, max_len + 1, MAX_PACKED_LEN
+            );
+        }
+        if let Some(first) = duplicates.first() {
+            warn!(
+                "{} whitelist entries repeat an earlier entry and are never reported (first on line {})",
+                duplicates.len(), first
+            );
         }
 
-        let len = if sizes.len() == 1 {
-            sizes.into_iter().next().unwrap()
-        } else {
-            anyhow::bail!("Barcodes have different lengths");
-        };
+        let mut ambiguous = HashMap::new();
+        if tolerance.corrects() {
+            if tolerance.mismatches > 1 {
+                Self::check_distances(&parents, tolerance.mismatches)?;
+            }
+            ambiguous = Self::insert_substitutions(&mut map, &parents, tolerance);
+            if tolerance.indels {
+                Self::insert_indels(&mut map, &ambiguous);
+            }
+            if !ambiguous.is_empty() && tolerance.mismatches > 1 {
+                warn!(
+                    "{} neighbours within {} mismatches are shared by different barcodes and will not be corrected",
+                    ambiguous.len(), tolerance.mismatches
+                );
+            } else {
+                info!("{} ambiguous neighbours removed", ambiguous.len());
+            }
+        }
+        let mut lengths = sizes.into_iter().collect::<Vec<_>>();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        let mut key_lengths = map
+            .keys()
+            .map(|k| k.len())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        key_lengths.sort_unstable_by(|a, b| b.cmp(a));
 
         let spacer_len = first_spacer.map(|s| s.seq().len());
-        
+
+        let mut packed = PackedMap::default();
+        for (key, entry) in map {
+            if !packed.insert(&key, entry) {
+                anyhow::bail!(
+                    "Barcode {} can't be indexed: only A, C, G, T and up to {} nucleotides (including the spacer) are supported",
+                    String::from_utf8_lossy(&key),
+                    MAX_PACKED_LEN
+                );
+            }
+        }
+
         Ok(Self {
-            map,
+            map: packed,
             index,
-            len,
+            names,
+            lengths,
+            key_lengths,
             spacer_len,
+            mismatches: tolerance.mismatches,
+            spacer_mismatches: tolerance.spacer_mismatches,
+            indels: tolerance.indels,
+            parents,
+            ambiguous,
+            min_posterior: tolerance.min_posterior,
+            max_n: tolerance.max_n,
         })
     }
 
+    /// Refuses a mismatch budget under which a whitelist entry lies within
+    /// the neighbourhood of a different entry
+    fn check_distances(parents: &[Parent], mismatches: usize) -> Result<()> {
+        for (i, a) in parents.iter().enumerate() {
+            for b in parents.iter().skip(i + 1) {
+                if a.id == b.id || a.seq.len() != b.seq.len() {
+                    continue;
+                }
+                let dist = hamming(&a.seq, &b.seq);
+                if dist <= mismatches {
+                    anyhow::bail!(
+                        "{} mismatches are not unique: {} and {} differ at only {} positions",
+                        mismatches,
+                        String::from_utf8_lossy(&a.seq),
+                        String::from_utf8_lossy(&b.seq),
+                        dist
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds all unambiguous substitution neighbours of the whitelist entries
+    /// to the map. With a separate spacer budget the barcode and the spacer
+    /// are limited independently, otherwise they share the barcode budget.
+    /// Neighbours reached from different barcodes are dropped and returned
+    /// with the indices of their parents, whitelist entries always take
+    /// priority over neighbours
+    fn insert_substitutions(
+        map: &mut HashMap<Vec<u8>, Entry>,
+        parents: &[Parent],
+        tolerance: &Tolerance,
+    ) -> HashMap<Vec<u8>, Vec<usize>> {
+        let max_dist = tolerance.mismatches + tolerance.spacer_mismatches.unwrap_or(0);
+        let mut aliases: HashMap<Vec<u8>, Vec<(usize, usize, usize)>> = HashMap::new();
+        for (p_idx, parent) in parents.iter().enumerate() {
+            for (child, dist) in Self::substitution_variants(&parent.seq, max_dist) {
+                if map.contains_key(&child) {
+                    continue;
+                }
+                let barcode_dist = hamming(&child[..parent.barcode_len], &parent.seq[..parent.barcode_len]);
+                let spacer_dist = dist - barcode_dist;
+                let allowed = match tolerance.spacer_mismatches {
+                    Some(spacer_mismatches) => {
+                        barcode_dist <= tolerance.mismatches && spacer_dist <= spacer_mismatches
+                    }
+                    None => dist <= tolerance.mismatches,
+                };
+                if allowed {
+                    aliases.entry(child).or_default().push((p_idx, dist, spacer_dist));
+                }
+            }
+        }
+
+        let mut ambiguous = HashMap::new();
+        for (child, hits) in aliases {
+            let id = parents[hits[0].0].id;
+            if hits.iter().all(|&(p_idx, _, _)| parents[p_idx].id == id) {
+                let &(_, dist, spacer_dist) = hits.iter().min_by_key(|&&(_, dist, _)| dist).unwrap();
+                let entry = Entry {
+                    id,
+                    correction: Correction::Substitution(dist as u8),
+                    spacer_errors: spacer_dist as u8,
+                };
+                map.insert(child, entry);
+            } else {
+                ambiguous.insert(child, hits.into_iter().map(|(p_idx, _, _)| p_idx).collect());
+            }
+        }
+        ambiguous
+    }
+
+    /// Returns all sequences within `max_dist` substitutions of the sequence
+    /// together with their distance
+    fn substitution_variants(sequence: &[u8], max_dist: usize) -> Vec<(Vec<u8>, usize)> {
+        let mut variants = Vec::new();
+        let mut current = sequence.to_vec();
+        Self::substitute(&mut current, sequence, 0, 0, max_dist, &mut variants);
+        variants
+    }
+
+    fn substitute(
+        current: &mut Vec<u8>,
+        original: &[u8],
+        start: usize,
+        dist: usize,
+        max_dist: usize,
+        variants: &mut Vec<(Vec<u8>, usize)>,
+    ) {
+        if dist == max_dist {
+            return;
+        }
+        for i in start..original.len() {
+            for &nuc in NUCLEOTIDES.iter() {
+                if nuc == original[i] {
+                    continue;
+                }
+                current[i] = nuc;
+                variants.push((current.clone(), dist + 1));
+                Self::substitute(current, original, i + 1, dist + 1, max_dist, variants);
+            }
+            current[i] = original[i];
+        }
+    }
+
+    /// Adds all unambiguous single insertion and deletion neighbours of the
+    /// whitelist entries to the map.
+    /// Neighbours which collide with a neighbour of a different barcode are removed,
+    /// whitelist entries and substitution neighbours of the same barcode take priority
+    fn insert_indels(
+        map: &mut HashMap<Vec<u8>, Entry>,
+        ambiguous: &HashMap<Vec<u8>, Vec<usize>>,
+    ) {
+        let mut aliases: HashMap<Vec<u8>, Option<BarcodeID>> = HashMap::new();
+        for (parent, entry) in map.iter() {
+            if entry.correction != Correction::Exact {
+                continue;
+            }
+            let id = entry.id;
+            for child in Self::indel_variants(parent) {
+                aliases
+                    .entry(child)
+                    .and_modify(|e| {
+                        if *e != Some(id) {
+                            *e = None
+                        }
+                    })
+                    .or_insert(Some(id));
+            }
+        }
+
+        for (child, id) in aliases {
+            if ambiguous.contains_key(&child) {
+                continue;
+            }
+            match (map.get(&child), id) {
+                (None, Some(id)) => {
+                    map.insert(child, Entry::new(id, Correction::Indel));
+                }
+                (None, None) => {}
+                (Some(entry), id) => {
+                    if entry.correction != Correction::Exact && id != Some(entry.id) {
+                        map.remove(&child);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns all sequences with a single inserted or deleted nucleotide.
+    /// Only interior positions are edited, as an indel at either end of the
+    /// sequence is indistinguishable from a shift or a substitution
+    fn indel_variants(sequence: &[u8]) -> Vec<Vec<u8>> {
+        let mut variants = Vec::new();
+        for i in 1..sequence.len().saturating_sub(1) {
+            let mut deletion = sequence.to_vec();
+            deletion.remove(i);
+            variants.push(deletion);
+        }
+        for i in 1..sequence.len() {
+            for &nuc in NUCLEOTIDES.iter() {
+                let mut insertion = sequence.to_vec();
+                insertion.insert(i, nuc);
+                variants.push(insertion);
+            }
+        }
+        variants
+    }
+
     /// Reads a sequence from a line and appends a spacer if given
     /// Returns the sequence as a vector of integer nucleotides
     fn read_sequence(line: &str, spacer: Option<&Spacer>) -> Vec<u8> {
-        let barcode = line.trim().as_bytes().to_vec();
+        let mut barcode = line.trim().as_bytes().to_vec();
         if let Some(spacer) = spacer {
-            let mut barcode_with_spacer = barcode.clone();
-            barcode_with_spacer.extend_from_slice(spacer.seq());
-            return barcode_with_spacer;
-        } else {
-            return barcode;
+            barcode.extend_from_slice(spacer.seq());
         }
+        barcode
     }
 
     /// Checks if a sequence contains a barcode as a substring
     /// and returns the position of the first nucleotide after the barcode
     /// as well as the barcode index
+    #[allow(dead_code)]
     pub fn match_sequence(&self, sequence: &[u8]) -> Option<(EndPos, BarcodeID)> {
-        if sequence.len() < self.len {
+        self.match_sequence_with_quality(sequence, None)
+            .map(|m| (m.end, m.id))
+    }
+
+    /// Checks if a sequence contains a barcode as a substring.
+    /// Every start position is considered and the least corrected match wins,
+    /// the earliest position breaks ties. At each position the least corrected
+    /// and then the longest key is chosen, so the end position reflects an
+    /// insertion or deletion in the barcode. Equally good keys of different
+    /// barcodes, at any position or of any length, flag the match as ambiguous.
+    /// If qualities are given ambiguous neighbours can be resolved,
+    /// windows containing N are compared against the whitelist last
+    pub fn match_sequence_with_quality(
+        &self,
+        sequence: &[u8],
+        quality: Option<&[u8]>,
+    ) -> Option<BarcodeMatch> {
+        self.match_window(sequence, quality, sequence.len())
+    }
+
+    /// Matches keys ending up to `limit`, insertion keys (longer than
+    /// the barcodes) may end one nucleotide after it
+    fn match_window(
+        &self,
+        sequence: &[u8],
+        quality: Option<&[u8]>,
+        limit: usize,
+    ) -> Option<BarcodeMatch> {
+        let min_len = self.key_lengths[self.key_lengths.len() - 1];
+        let num_windows = (limit + 1).saturating_sub(min_len);
+        // the keys of each position are tried longest first
+        let candidates = PackedWindows::new(sequence, self.key_lengths[0]).take(num_windows).flat_map(|packed| {
+            let pos = packed.pos;
+            self.key_lengths
+                .iter()
+                .filter(move |&&len| pos + len <= sequence.len() && pos + len.min(self.len()) <= limit)
+                .filter_map(move |&len| {
+                    let window = &sequence[pos..pos + len];
+                    let qual = quality.and_then(|q| q.get(pos..pos + len));
+                    self.lookup(window, packed.prefix(len), qual).map(|entry| BarcodeMatch {
+                        start: pos,
+                        end: pos + len,
+                        id: entry.id,
+                        correction: entry.correction,
+                        spacer_errors: entry.spacer_errors,
+                        ambiguous: false,
+                    })
+                })
+        });
+        best_match(candidates)
+    }
+
+    /// Looks up a window by its packed code, falling back to quality based
+    /// resolution of ambiguous neighbours and to wildcard matching of N bases
+    fn lookup(&self, window: &[u8], code: Option<u64>, quality: Option<&[u8]>) -> Option<Entry> {
+        code.and_then(|code| self.map.get_packed(window.len(), code))
+            .copied()
+            .or_else(|| {
+                self.resolve(window, quality?)
+                    .map(|id| Entry::new(id, Correction::Resolved))
+            })
+            .or_else(|| {
+                self.match_wildcard(window)
+                    .map(|id| Entry::new(id, Correction::Wildcard))
+            })
+    }
+
+    /// Matches the barcode directly preceding an anchor.
+    /// The region is the sequence before the anchor and `spacer` the expected
+    /// spacer sequence that is appended to the observed barcode, so only
+    /// errors within the barcode are counted. Leading nucleotides of the
+    /// region that don't belong to the barcode are ignored.
+    /// The end of the match is the length of the region
+    pub fn match_before(
+        &self,
+        region: &[u8],
+        quality: Option<&[u8]>,
+        spacer: &[u8],
+    ) -> Option<BarcodeMatch> {
+        self.key_lengths
+            .iter()
+            .filter(|&&len| len >= spacer.len() && len - spacer.len() <= region.len())
+            .filter_map(|&len| {
+                let start = region.len() + spacer.len() - len;
+                let mut key = region[start..].to_vec();
+                key.extend_from_slice(spacer);
+                let qual = quality.and_then(|q| q.get(start..)).map(|q| {
+                    let mut qual = q.to_vec();
+                    qual.resize(len, b'I');
+                    qual
+                });
+                self.lookup(&key, encode(&key), qual.as_deref()).map(|entry| (entry, len))
+            })
+            .min_by_key(|&(entry, len)| (entry.correction, entry.spacer_errors, Reverse(len)))
+            .map(|(entry, len)| BarcodeMatch {
+                start: region.len() + spacer.len() - len,
+                end: region.len(),
+                id: entry.id,
+                correction: entry.correction,
+                spacer_errors: entry.spacer_errors,
+                ambiguous: false,
+            })
+    }
+
+    /// Matches a window containing N bases against all whitelist entries.
+    /// N positions match any nucleotide, the remaining positions may differ
+    /// within the mismatch budget. Returns the barcode if only a single
+    /// whitelist entry is compatible
+    fn match_wildcard(&self, window: &[u8]) -> Option<BarcodeID> {
+        let num_n = window.iter().filter(|&&b| b == b'N').count();
+        if num_n == 0 || num_n > self.max_n {
             return None;
         }
-        sequence
-            .windows(self.len)
-            .position(|window| self.map.contains_key(window))
-            .map(|pos| {
-                (
-                    pos + self.len,
-                    *self.map.get(&sequence[pos..pos + self.len]).unwrap(),
-                )
-            })
+        let max_dist = self.mismatches + self.spacer_mismatches.unwrap_or(0);
+        let mut found = None;
+        for parent in self.parents.iter() {
+            if parent.seq.len() != window.len() {
+                continue;
+            }
+            let dist = window
+                .iter()
+                .zip(&parent.seq)
+                .filter(|(&observed, &expected)| observed != b'N' && observed != expected)
+                .count();
+            if dist <= max_dist {
+                match found {
+                    None => found = Some(parent.id),
+                    Some(other) if other != parent.id => return None,
+                    _ => {}
+                }
+            }
+        }
+        found
+    }
+
+    /// Resolves an ambiguous neighbour by the base qualities.
+    /// Each candidate barcode implies errors at the positions where it differs
+    /// from the observed sequence, the candidate with the highest posterior
+    /// probability is returned if it reaches the threshold
+    fn resolve(&self, window: &[u8], quality: &[u8]) -> Option<BarcodeID> {
+        let min_posterior = self.min_posterior?;
+        let candidates = self.ambiguous.get(window)?;
+
+        let mut likelihoods: Vec<(BarcodeID, f64)> = Vec::new();
+        for &p_idx in candidates {
+            let parent = &self.parents[p_idx];
+            let id = &parent.id;
+            let likelihood = window
+                .iter()
+                .zip(&parent.seq)
+                .zip(quality)
+                .map(|((observed, expected), &q)| {
+                    let error = phred_to_error(q);
+                    if observed == expected {
+                        1.0 - error
+                    } else {
+                        error / 3.0
+                    }
+                })
+                .product::<f64>();
+            match likelihoods.iter_mut().find(|(other, _)| other == id) {
+                Some((_, l)) => *l += likelihood,
+                None => likelihoods.push((*id, likelihood)),
+            }
+        }
+
+        let total = likelihoods.iter().map(|(_, l)| l).sum::<f64>();
+        let (id, best) = likelihoods
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        if total > 0.0 && best / total >= min_posterior {
+            Some(id)
+        } else {
+            None
+        }
     }
 
     /// Matches a subsequence of a sequence
     /// and returns the position of the first nucleotide after the barcode
     /// as well as the barcode index
+    #[allow(dead_code)]
     pub fn match_subsequence(
         &self,
         sequence: &[u8],
         start: usize,
         end: usize,
     ) -> Option<(EndPos, BarcodeID)> {
+        self.match_subsequence_with_quality(sequence, None, start, end)
+            .map(|m| (m.end, m.id))
+    }
+
+    /// Matches a subsequence of a sequence and its qualities.
+    /// With indels an insertion may end one nucleotide after `end`
+    pub fn match_subsequence_with_quality(
+        &self,
+        sequence: &[u8],
+        quality: Option<&[u8]>,
+        start: usize,
+        end: usize,
+    ) -> Option<BarcodeMatch> {
         if start > sequence.len() || end > sequence.len() || start > end {
             return None;
         }
-        self.match_sequence(&sequence[start..end])
+        let stop = (end + usize::from(self.indels)).min(sequence.len());
+        let quality = quality.and_then(|q| q.get(start..stop));
+        self.match_window(&sequence[start..stop], quality, end - start)
     }
 
     /// Returns the barcode sequence for a given index
     pub fn get_barcode(&self, idx: usize, with_spacer: bool) -> Option<&[u8]> {
-        let end_pos = if with_spacer {
-            self.len
+        let spacer_len = if with_spacer {
+            0
         } else {
-            if let Some(spacer_len) = self.spacer_len {
-                self.len - spacer_len
-            } else {
-                self.len
-            }
+            self.spacer_len.unwrap_or(0)
         };
-        self.index.get(&idx).map(|bc| &bc[..end_pos])
+        self.index.get(&idx).map(|bc| &bc[..bc.len() - spacer_len])
+    }
+
+    /// Returns the name of the whitelist entry with a given index
+    pub fn get_name(&self, idx: usize) -> Option<&str> {
+        self.names.get(&idx).map(|name| name.as_str())
+    }
+
+    /// Returns the number of whitelist entries
+    pub fn num_entries(&self) -> usize {
+        self.index.len()
     }
 
     /// Returns the barcode index for a given sequence
     #[allow(dead_code)]
     pub fn get_id(&self, barcode: &[u8]) -> Option<usize> {
-        self.map.get(barcode).map(|id| *id)
+        self.map.get(barcode).map(|entry| entry.id)
     }
 
-    /// Returns the length of each barcode
+    /// Returns the length of the longest barcode
     pub fn len(&self) -> usize {
-        self.len
+        self.lengths[0]
+    }
+
+    /// Returns the maximum number of corrected substitutions
+    pub fn mismatches(&self) -> usize {
+        self.mismatches
+    }
 
+    /// Returns the maximum number of corrected substitutions in the spacer
+    pub fn spacer_mismatches(&self) -> Option<usize> {
+        self.spacer_mismatches
+    }
+
+    /// Returns the length of the spacer appended to each barcode
+    pub fn spacer_len(&self) -> usize {
+        self.spacer_len.unwrap_or(0)
+    }
+
+    /// Returns true if barcodes are corrected for a single insertion or deletion
+    pub fn indels(&self) -> bool {
+        self.indels
+    }
+
+    /// Returns the number of neighbours shared by different barcodes
+    #[allow(dead_code)]
+    pub fn num_ambiguous(&self) -> usize {
+        self.ambiguous.len()
+    }
+
+    /// Returns the length of the shortest barcode
+    pub fn min_len(&self) -> usize {
+        self.lengths[self.lengths.len() - 1]
     }
 
     #[allow(dead_code)]
     pub fn to_str(&self) -> String {
         let mut s = String::new();
-        let meta_len = format!("Barcodes length: {} spacer: {}\n", self.len, self.spacer_len.unwrap_or(0));
-        let meta_map = format!("Barcode map size: {}\n", self.map.keys().len());
+        let meta_len = format!("Barcodes length: {:?} spacer: {}\n", self.lengths, self.spacer_len.unwrap_or(0));
+        let meta_map = format!(
+            "Barcode map size: {} mismatches: {} ambiguous: {}\n",
+            self.map.len(),
+            self.mismatches,
+            self.ambiguous.len()
+        );
         let meta_index = format!("Barcode index size: {}\n", self.index.len());
         s.push_str(&meta_len);
         s.push_str(&meta_map);
@@ -184,20 +808,77 @@ impl Barcodes {
         entries.sort_by_key(|&(idx, _)| idx);
         s.push_str("index:\n");
         for (idx, barcode) in entries {
-            s.push_str(&format!("{}: {}\n", idx, String::from_utf8_lossy(barcode)));
+            match self.names.get(idx) {
+                Some(name) => s.push_str(&format!("{}: {} ({})\n", idx, String::from_utf8_lossy(barcode), name)),
+                None => s.push_str(&format!("{}: {}\n", idx, String::from_utf8_lossy(barcode))),
+            }
         }
         s
     }
 
 }
 
+/// A placement of a barcode, optionally with how it was placed
+pub trait Placement {
+    fn barcode_match(&self) -> &BarcodeMatch;
+    fn barcode_match_mut(&mut self) -> &mut BarcodeMatch;
+}
+
+impl Placement for BarcodeMatch {
+    fn barcode_match(&self) -> &BarcodeMatch {
+        self
+    }
+    fn barcode_match_mut(&mut self) -> &mut BarcodeMatch {
+        self
+    }
+}
+
+impl<T> Placement for (BarcodeMatch, T) {
+    fn barcode_match(&self) -> &BarcodeMatch {
+        &self.0
+    }
+    fn barcode_match_mut(&mut self) -> &mut BarcodeMatch {
+        &mut self.0
+    }
+}
+
+/// Returns the best scoring of several placements of a barcode, given in order
+/// of preference for equally good matches. The match is flagged as ambiguous
+/// if a different barcode scores equally well
+pub fn best_match<P: Placement>(candidates: impl IntoIterator<Item = P>) -> Option<P> {
+    let mut best: Option<P> = None;
+    for candidate in candidates {
+        let m = candidate.barcode_match();
+        match &mut best {
+            Some(b) if m.score() == b.barcode_match().score() => {
+                if m.id != b.barcode_match().id {
+                    b.barcode_match_mut().ambiguous = true;
+                }
+            }
+            Some(b) if m.score() > b.barcode_match().score() => {}
+            _ => best = Some(candidate),
+        }
+    }
+    best
+}
+
+/// Converts a phred+33 quality character to an error probability
+fn phred_to_error(quality: u8) -> f64 {
+    10f64.powf(-(quality.saturating_sub(33) as f64) / 10.0)
+}
+
+/// Returns the number of differing positions of two equal length sequences
+pub fn hamming(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| x != y).count()
+}
+
 pub struct Spacer {
     seq: Vec<u8>,
 }
 impl Spacer {
     pub fn from_str(seq: &str) -> Vec<Self> {
         seq.split(',')
-            .map(|s| Self::new(s))
+            .map(Self::new)
             .collect()
     }
 
@@ -212,13 +893,133 @@ impl Spacer {
     }
 }
 
+/// A constant sequence (e.g. a spacer) that is searched for in a read
+/// to locate the adjacent barcode or UMI
+pub struct Anchor {
+    variants: Vec<Spacer>,
+    mismatches: usize,
+}
+
+/// The location of an anchor within a read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnchorHit {
+    pub start: usize,
+    pub end: usize,
+    pub variant: usize,
+    pub mismatches: usize,
+}
+
+impl Anchor {
+    pub fn new(variants: Vec<Spacer>, mismatches: usize) -> Self {
+        Self { variants, mismatches }
+    }
+
+    /// Searches the anchor starting at any position from `first` to `last`
+    /// (inclusive). The hit with the fewest mismatches is returned, ties are
+    /// broken by the earliest position
+    pub fn find(&self, sequence: &[u8], first: usize, last: usize) -> Option<AnchorHit> {
+        let mut best: Option<AnchorHit> = None;
+        for start in first..=last {
+            for (variant, spacer) in self.variants.iter().enumerate() {
+                let end = start + spacer.seq().len();
+                if end > sequence.len() {
+                    continue;
+                }
+                let mismatches = hamming(&sequence[start..end], spacer.seq());
+                if mismatches > self.mismatches {
+                    continue;
+                }
+                if best.is_none_or(|b| mismatches < b.mismatches) {
+                    best = Some(AnchorHit { start, end, variant, mismatches });
+                }
+            }
+            if best.is_some_and(|b| b.mismatches == 0) {
+                break;
+            }
+        }
+        best
+    }
+
+    /// Returns the sequence of an anchor variant
+    pub fn seq(&self, variant: usize) -> &[u8] {
+        self.variants[variant].seq()
+    }
+}
+
+/// A segment of variable length in front of the first barcode (e.g. a phase block).
+/// Optionally its sequence must be part of an onlist
+pub struct Stagger {
+    /// Position of the segment in the read
+    start: usize,
+    min_len: usize,
+    max_len: usize,
+    onlist: Option<HashSet<Vec<u8>>>,
+}
+
+impl Stagger {
+    pub fn new(start: usize, min_len: usize, max_len: usize) -> Result<Self> {
+        if min_len > max_len {
+            anyhow::bail!("Stagger min_len {} is larger than max_len {}", min_len, max_len);
+        }
+        Ok(Self { start, min_len, max_len, onlist: None })
+    }
+
+    #[allow(dead_code)]
+    pub fn with_onlist_file(self, path: &str) -> Result<Self> {
+        let reader = File::open(path).map(BufReader::new)?;
+        self.with_onlist(reader)
+    }
+
+    /// Restricts the segment to the sequences of an onlist (one per line,
+    /// skipping blank lines and `#` comments)
+    pub fn with_onlist<R: BufRead>(mut self, reader: R) -> Result<Self> {
+        let mut onlist = HashSet::new();
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            let seq = line.trim();
+            if seq.is_empty() || seq.starts_with('#') {
+                continue;
+            }
+            if seq.len() < self.min_len || seq.len() > self.max_len {
+                anyhow::bail!(
+                    "Stagger {} on line {} is outside of the length range {}-{}",
+                    seq, idx + 1, self.min_len, self.max_len
+                );
+            }
+            onlist.insert(seq.as_bytes().to_vec());
+        }
+        self.onlist = Some(onlist);
+        Ok(self)
+    }
+
+    /// Returns the valid segment lengths of a sequence, shortest first
+    pub fn lengths<'a>(&'a self, sequence: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        (self.min_len..=self.max_len).filter(move |&len| match &self.onlist {
+            Some(onlist) => sequence
+                .get(self.start..self.start + len)
+                .is_some_and(|seq| onlist.contains(seq)),
+            None => true,
+        })
+    }
+
+    /// Returns the position of the segment in the read
+    pub fn start(&self) -> usize {
+        self.start
+    }
+}
+
 #[cfg(test)]
 mod testing {
     use super::*;
 
     const TEST_FILE: &str = "data/barcodes_v3/fb_v3_bc1.tsv";
     const TEST_BUFFER: &[u8] = b"AGAAACCA\nGATTTCCC\nAAGTCCAA\nGAGAAACC";
-    const MALFORMED_BUFFER: &[u8] = b"AGAAACCA\nGATTTCCC\nAAGTCCAA\nGAGAAACCC";
+    const MIXED_LENGTH_BUFFER: &[u8] = b"AGAAACCA\nGATTTCCC\nAAGTCCAA\nGAGAAACCC";
+    const EMPTY_LINE_BUFFER: &[u8] = b"AGAAACCA\n\nAAGTCCAA\nGAGAAACC";
+    const STAGGERED_BUFFER: &[u8] = b"AGTAGAAACCA\nGTGATTTCCC\nTAAGTCCAA\nGAGAAACC";
+    const NAMED_BUFFER: &[u8] = b"AGAAACCA\tA01\nGATTTCCC\nAAGTCCAA\tA03 \n";
+    const CLOSE_BUFFER: &[u8] = b"AGAAACCA\nAGAAACGG";
+    const DISTANT_BUFFER: &[u8] = b"AAAAAAAA\nCCCCCCCC";
     const TEST_SPACER: &str = "ATG";
     const NOMATCH_SEQ: &[u8] = b"SHOULDNOTMATCHANYTHING";
     const ENDMATCH_SEQ: &[u8] = b"OFFSETXAGAAACCA";
@@ -227,6 +1028,8 @@ mod testing {
     const STARTMATCH_SEQ_1D: &[u8] = b"TGAAACCAANDSOMETHINGELSE";
     const OFFSETMATCH_SEQ: &[u8] = b"123AGAAACCASOMETHINGELSE";
     const OFFSETMATCH_SEQ_1D: &[u8] = b"123TGAAACCASOMETHINGELSE";
+    const DELETION_SEQ: &[u8] = b"AGAACCATTTTTT";
+    const INSERTION_SEQ: &[u8] = b"AGAAGACCATTTT";
 
     #[test]
     fn from_file() {
@@ -267,6 +1070,17 @@ mod testing {
         assert_eq!(barcodes.get_id(b"GAGAAACC").unwrap(), 3);
     }
 
+    #[test]
+    fn from_buffer_named() {
+        let barcodes = Barcodes::from_buffer(NAMED_BUFFER, true).unwrap();
+        assert_eq!(barcodes.num_entries(), 3);
+        assert_eq!(barcodes.get_id(b"AAGTCCAA"), Some(2));
+        assert_eq!(barcodes.get_barcode(0, true).unwrap(), b"AGAAACCA");
+        assert_eq!(barcodes.get_name(0), Some("A01"));
+        assert_eq!(barcodes.get_name(1), None);
+        assert_eq!(barcodes.get_name(2), Some("A03"));
+    }
+
     #[test]
     fn from_buffer() {
         let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
@@ -298,6 +1112,131 @@ mod testing {
         assert_eq!(barcodes.get_id(b"CCGAAACC"), None);
     }
 
+    #[test]
+    fn from_buffer_two_mismatches() {
+        let tolerance = Tolerance { mismatches: 2, ..Tolerance::default() };
+        let barcodes = Barcodes::parse_buffer(DISTANT_BUFFER, &[], &tolerance).unwrap();
+        assert_eq!(barcodes.mismatches(), 2);
+        assert_eq!(barcodes.num_ambiguous(), 0);
+        assert_eq!(barcodes.map.len(), 2 * (1 + 8 * 3 + 28 * 9));
+
+        assert_eq!(barcodes.get_id(b"AAAAAAAA").unwrap(), 0);
+        assert_eq!(barcodes.get_id(b"TAAAAAAA").unwrap(), 0);
+        assert_eq!(barcodes.get_id(b"TAAAAAAG").unwrap(), 0);
+        assert_eq!(barcodes.get_id(b"TCAAAAAG"), None);
+        assert_eq!(barcodes.get_id(b"CCCCCCAA").unwrap(), 1);
+    }
+
+    #[test]
+    fn from_buffer_two_mismatches_colliding() {
+        let tolerance = Tolerance { mismatches: 2, ..Tolerance::default() };
+        let barcodes = Barcodes::parse_buffer(CLOSE_BUFFER, &[], &tolerance);
+        assert!(barcodes.is_err());
+    }
+
+    #[test]
+    fn separate_spacer_mismatches() {
+        let spacer = Spacer::from_str(TEST_SPACER);
+        let tolerance = Tolerance {
+            spacer_mismatches: Some(1),
+            ..Tolerance::new(false, false)
+        };
+        let barcodes = Barcodes::parse_buffer(DISTANT_BUFFER, &spacer, &tolerance).unwrap();
+        assert_eq!(barcodes.map.len(), 2 * (1 + 8 * 3) * (1 + 3 * 3));
+
+        // one error in the barcode and one in the spacer
+        let m = barcodes.match_sequence_with_quality(b"TAAAAAAAATC", None).unwrap();
+        assert_eq!((m.id, m.correction, m.spacer_errors), (0, Correction::Substitution(2), 1));
+        assert!(m.barcode_corrected());
+
+        // only the spacer
+        let m = barcodes.match_sequence_with_quality(b"AAAAAAAAATC", None).unwrap();
+        assert_eq!((m.id, m.correction, m.spacer_errors), (0, Correction::Substitution(1), 1));
+        assert!(!m.barcode_corrected());
+
+        // two errors in the spacer or in the barcode
+        assert_eq!(barcodes.match_sequence(b"AAAAAAAAACC"), None);
+        assert_eq!(barcodes.match_sequence(b"TTAAAAAAATG"), None);
+    }
+
+    #[test]
+    fn shared_spacer_mismatches() {
+        let spacer = Spacer::from_str(TEST_SPACER);
+        let barcodes = Barcodes::from_buffer_with_spacer(DISTANT_BUFFER, &spacer, false).unwrap();
+        assert_eq!(barcodes.match_sequence(b"TAAAAAAAATC"), None);
+        let m = barcodes.match_sequence_with_quality(b"AAAAAAAAATC", None).unwrap();
+        assert_eq!((m.id, m.correction, m.spacer_errors), (0, Correction::Substitution(1), 1));
+    }
+
+    #[test]
+    fn resolve_ambiguous() {
+        let tolerance = Tolerance {
+            min_posterior: Some(0.95),
+            ..Tolerance::new(false, false)
+        };
+        let barcodes = Barcodes::parse_buffer(CLOSE_BUFFER, &[], &tolerance).unwrap();
+        assert_eq!(barcodes.num_ambiguous(), 2);
+        assert_eq!(barcodes.get_id(b"AGAAACGA"), None);
+
+        // the error is most likely at the low quality position
+        let m = barcodes.match_sequence_with_quality(b"AGAAACGA", Some(b"IIIIII#I"));
+        assert_eq!(m.map(|m| (m.id, m.correction)), Some((0, Correction::Resolved)));
+        let m = barcodes.match_sequence_with_quality(b"AGAAACGA", Some(b"IIIIIII#"));
+        assert_eq!(m.map(|m| (m.id, m.correction)), Some((1, Correction::Resolved)));
+
+        // equal qualities can not be resolved
+        assert_eq!(barcodes.match_sequence_with_quality(b"AGAAACGA", Some(b"IIIIIIII")), None);
+        assert_eq!(barcodes.match_sequence(b"AGAAACGA"), None);
+    }
+
+    #[test]
+    fn resolve_ambiguous_disabled() {
+        let barcodes = Barcodes::from_buffer(CLOSE_BUFFER, false).unwrap();
+        assert_eq!(barcodes.match_sequence_with_quality(b"AGAAACGA", Some(b"IIIIII#I")), None);
+    }
+
+    #[test]
+    fn match_wildcard() {
+        let tolerance = Tolerance {
+            max_n: 2,
+            ..Tolerance::new(false, false)
+        };
+        let barcodes = Barcodes::parse_buffer(TEST_BUFFER, &[], &tolerance).unwrap();
+
+        // two Ns, or an N and a mismatch
+        let m = barcodes.match_sequence_with_quality(b"AGANACNA", None).unwrap();
+        assert_eq!((m.end, m.id, m.correction), (8, 0, Correction::Wildcard));
+        let m = barcodes.match_sequence_with_quality(b"TGAAACNA", None).unwrap();
+        assert_eq!((m.end, m.id, m.correction), (8, 0, Correction::Wildcard));
+
+        // too many Ns
+        assert_eq!(barcodes.match_sequence(b"ANANACNA"), None);
+        // N and two mismatches
+        assert_eq!(barcodes.match_sequence(b"TCAAACNA"), None);
+    }
+
+    #[test]
+    fn match_wildcard_ambiguous() {
+        let tolerance = Tolerance {
+            max_n: 1,
+            ..Tolerance::new(true, false)
+        };
+        let barcodes = Barcodes::parse_buffer(CLOSE_BUFFER, &[], &tolerance).unwrap();
+        assert_eq!(barcodes.match_sequence(b"AGAAACNA"), Some((8, 0)));
+        assert_eq!(barcodes.match_sequence(b"AGAAACGN"), Some((8, 1)));
+        // compatible with both entries
+        assert_eq!(barcodes.match_sequence(b"AGAAANNA"), None);
+        assert_eq!(barcodes.match_sequence(b"AGAAACNN"), None);
+    }
+
+    #[test]
+    fn match_wildcard_disabled() {
+        // N is never part of the correction neighbourhood
+        let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
+        assert_eq!(barcodes.match_sequence(b"AGAAACNA"), None);
+        assert_eq!(barcodes.match_sequence(b"AGANACNA"), None);
+    }
+
     #[test]
     fn from_file_with_spacer() {
         let spacer = Spacer::from_str(TEST_SPACER);
@@ -369,28 +1308,64 @@ mod testing {
 
     #[test]
     fn size_variance() {
-        let barcodes = Barcodes::from_buffer(MALFORMED_BUFFER, false);
-        assert!(barcodes.is_err());
+        let barcodes = Barcodes::from_buffer(MIXED_LENGTH_BUFFER, false).unwrap();
+        assert_eq!(barcodes.len(), 9);
+        assert_eq!(barcodes.min_len(), 8);
+        assert_eq!(barcodes.index.len(), 4);
+        assert_eq!(barcodes.get_barcode(3, true).unwrap(), b"GAGAAACCC");
+        assert_eq!(barcodes.get_id(b"GAGAAACCC").unwrap(), 3);
+        assert_eq!(barcodes.get_id(b"TAGAAACCC").unwrap(), 3);
     }
 
     #[test]
     fn size_variance_exact() {
-        let barcodes = Barcodes::from_buffer(MALFORMED_BUFFER, true);
-        assert!(barcodes.is_err());
+        let barcodes = Barcodes::from_buffer(MIXED_LENGTH_BUFFER, true).unwrap();
+        assert_eq!(barcodes.len(), 9);
+        assert_eq!(barcodes.min_len(), 8);
+        assert_eq!(barcodes.map.len(), 4);
     }
 
     #[test]
     fn size_variance_with_spacer() {
         let spacer = Spacer::from_str(TEST_SPACER);
-        let barcodes = Barcodes::from_buffer_with_spacer(MALFORMED_BUFFER, &spacer, false);
-        assert!(barcodes.is_err());
+        let barcodes = Barcodes::from_buffer_with_spacer(MIXED_LENGTH_BUFFER, &spacer, false).unwrap();
+        assert_eq!(barcodes.len(), 12);
+        assert_eq!(barcodes.min_len(), 11);
+        assert_eq!(barcodes.get_barcode(3, true).unwrap(), b"GAGAAACCCATG");
+        assert_eq!(barcodes.get_barcode(3, false).unwrap(), b"GAGAAACCC");
+        assert_eq!(barcodes.get_barcode(0, false).unwrap(), b"AGAAACCA");
     }
 
     #[test]
     fn size_variance_with_spacer_exact() {
         let spacer = Spacer::from_str(TEST_SPACER);
-        let barcodes = Barcodes::from_buffer_with_spacer(MALFORMED_BUFFER, &spacer, true);
-        assert!(barcodes.is_err());
+        let barcodes = Barcodes::from_buffer_with_spacer(MIXED_LENGTH_BUFFER, &spacer, true).unwrap();
+        assert_eq!(barcodes.len(), 12);
+        assert_eq!(barcodes.map.len(), 4);
+    }
+
+    #[test]
+    fn empty_line() {
+        // blank lines are skipped and don't shift the indices
+        let barcodes = Barcodes::from_buffer(EMPTY_LINE_BUFFER, false).unwrap();
+        assert_eq!(barcodes.num_entries(), 3);
+        assert_eq!(barcodes.get_barcode(1, false).unwrap(), b"AAGTCCAA");
+        let barcodes = Barcodes::from_buffer(&b"# bc1\nAGAAACCA\nAAGTCCAA\n\n"[..], false).unwrap();
+        assert_eq!(barcodes.lengths, vec![8]);
+    }
+
+    #[test]
+    fn match_staggered() {
+        let barcodes = Barcodes::from_buffer(STAGGERED_BUFFER, true).unwrap();
+        assert_eq!(barcodes.len(), 11);
+        assert_eq!(barcodes.min_len(), 8);
+
+        // the end position follows the length of the matching entry
+        assert_eq!(barcodes.match_sequence(b"AGTAGAAACCAGGG"), Some((11, 0)));
+        assert_eq!(barcodes.match_sequence(b"GTGATTTCCCGGGG"), Some((10, 1)));
+        assert_eq!(barcodes.match_sequence(b"TAAGTCCAAGGGGG"), Some((9, 2)));
+        assert_eq!(barcodes.match_sequence(b"GAGAAACCGGGGGG"), Some((8, 3)));
+        assert_eq!(barcodes.match_subsequence(b"NNGTGATTTCCCGG", 2, 14), Some((10, 1)));
     }
 
     #[test]
@@ -405,7 +1380,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_sequence(STARTMATCH_SEQ),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_sequence(OFFSETMATCH_SEQ),
@@ -419,7 +1394,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_sequence(STARTMATCH_SEQ_1D),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_sequence(OFFSETMATCH_SEQ_1D),
@@ -439,7 +1414,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_sequence(STARTMATCH_SEQ),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_sequence(OFFSETMATCH_SEQ),
@@ -452,6 +1427,78 @@ mod testing {
         assert_eq!(barcodes.match_sequence(OFFSETMATCH_SEQ_1D), None);
     }
 
+    #[test]
+    fn match_sequence_indels() {
+        let tolerance = Tolerance::new(false, true);
+        let barcodes = Barcodes::parse_buffer(TEST_BUFFER, &[], &tolerance).unwrap();
+        assert_eq!(barcodes.len(), 8);
+
+        // exact and substitutions are unchanged
+        assert_eq!(barcodes.match_sequence(STARTMATCH_SEQ), Some((8, 0)));
+        assert_eq!(barcodes.match_sequence(STARTMATCH_SEQ_1D), Some((8, 0)));
+        assert_eq!(barcodes.match_sequence(OFFSETMATCH_SEQ), Some((11, 0)));
+
+        // the end position follows the deletion / insertion
+        assert_eq!(barcodes.match_sequence(DELETION_SEQ), Some((7, 0)));
+        assert_eq!(barcodes.match_sequence(INSERTION_SEQ), Some((9, 0)));
+        assert_eq!(barcodes.get_id(b"AGAACCA").unwrap(), 0);
+        assert_eq!(barcodes.get_id(b"AGAAGACCA").unwrap(), 0);
+    }
+
+    #[test]
+    fn match_sequence_indels_disabled() {
+        let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
+        assert_eq!(barcodes.get_id(b"AGAACCA"), None);
+        assert_eq!(barcodes.get_id(b"AGAAGACCA"), None);
+        assert_eq!(barcodes.match_sequence(DELETION_SEQ), None);
+    }
+
+    #[test]
+    fn match_sequence_indels_with_spacer() {
+        let spacer = Spacer::from_str(TEST_SPACER);
+        let tolerance = Tolerance::new(false, true);
+        let barcodes = Barcodes::parse_buffer(TEST_BUFFER, &spacer, &tolerance).unwrap();
+        assert_eq!(barcodes.len(), 11);
+
+        // deletion in the barcode, insertion in the spacer
+        assert_eq!(barcodes.match_sequence(b"AGAACCAATGNNN"), Some((10, 0)));
+        assert_eq!(barcodes.match_sequence(b"AGAAACCAACTGNNN"), Some((12, 0)));
+    }
+
+    #[test]
+    fn match_ambiguous_lengths() {
+        // both entries match exactly at the first position
+        let barcodes = Barcodes::parse_buffer(&b"AGAAACCA\nAGAAACC\n"[..], &[], &Tolerance::new(true, false)).unwrap();
+        let m = barcodes.match_sequence_with_quality(b"AGAAACCATT", None).unwrap();
+        assert_eq!((m.id, m.end), (0, 8));
+        assert!(m.ambiguous);
+        let m = barcodes.match_sequence_with_quality(b"AGAAACCTTT", None).unwrap();
+        assert_eq!((m.id, m.end), (1, 7));
+        assert!(!m.ambiguous);
+    }
+
+    #[test]
+    fn match_case_sensitive() {
+        let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
+        assert_eq!(barcodes.match_sequence(STARTMATCH_SEQ), Some((8, 0)));
+        let lower = STARTMATCH_SEQ.to_ascii_lowercase();
+        assert_eq!(barcodes.match_sequence(&lower), None);
+        assert_eq!(barcodes.get_id(b"agaaacca"), None);
+    }
+
+    #[test]
+    fn unindexable_entries() {
+        // an insertion into a 32 nt key can't be packed
+        let long = "ACGT".repeat(8);
+        let error = Barcodes::parse_buffer(long.as_bytes(), &[], &Tolerance::new(false, true)).unwrap_err();
+        assert!(error.to_string().contains("indels"));
+        assert!(Barcodes::parse_buffer(long.as_bytes(), &[], &Tolerance::new(false, false)).is_ok());
+        assert!(Barcodes::parse_buffer(&long.as_bytes()[1..], &[], &Tolerance::new(false, true)).is_ok());
+        // reads are matched case-sensitively
+        let error = Barcodes::parse_buffer(&b"AGAAACCA\nagaaacca\n"[..], &[], &Tolerance::new(true, false)).unwrap_err();
+        assert!(error.to_string().contains("line 2"));
+    }
+
     #[test]
     fn match_subsequence() {
         let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
@@ -465,7 +1512,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_subsequence(ENDMATCH_SEQ, start_pos, end_pos),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_subsequence(STARTMATCH_SEQ, start_pos, end_pos),
@@ -479,7 +1526,7 @@ mod testing {
         // with mismatch
         assert_eq!(
             barcodes.match_subsequence(ENDMATCH_SEQ_1D, start_pos, end_pos),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_subsequence(STARTMATCH_SEQ_1D, start_pos, end_pos),
@@ -504,7 +1551,7 @@ mod testing {
         );
         assert_eq!(
             barcodes.match_subsequence(ENDMATCH_SEQ, start_pos, end_pos),
-            Some((0 + barcodes.len(), 0))
+            Some((barcodes.len(), 0))
         );
         assert_eq!(
             barcodes.match_subsequence(STARTMATCH_SEQ, start_pos, end_pos),
@@ -543,4 +1590,137 @@ mod testing {
         assert_eq!(barcodes.match_sequence(b""), None);
         assert_eq!(barcodes.match_subsequence(b"", 0, barcodes.len()), None);
     }
+
+    #[test]
+    fn anchor_find() {
+        let anchor = Anchor::new(Spacer::from_str("TTCGAG"), 1);
+        let seq = b"ACGTTTCCAGACGTTCGAGAAA";
+        // exact hit is preferred over the earlier hit with a mismatch
+        let hit = anchor.find(seq, 0, 16).unwrap();
+        assert_eq!((hit.start, hit.end, hit.mismatches), (13, 19, 0));
+        // the hit with a mismatch is found if the exact one is out of range
+        let hit = anchor.find(seq, 0, 10).unwrap();
+        assert_eq!((hit.start, hit.mismatches), (4, 1));
+        assert_eq!(anchor.find(seq, 14, 30), None);
+    }
+
+    #[test]
+    fn match_before_anchor() {
+        let spacers = Spacer::from_str(TEST_SPACER);
+        let barcodes = Barcodes::parse_buffer(TEST_BUFFER, &spacers, &Tolerance::new(false, true)).unwrap();
+        let spacer = TEST_SPACER.as_bytes();
+
+        // leading nucleotides are ignored
+        let m = barcodes.match_before(b"NNNGATTTCCC", None, spacer).unwrap();
+        assert_eq!((m.end, m.id, m.correction), (11, 1, Correction::Exact));
+
+        // a mismatch in the barcode
+        let m = barcodes.match_before(b"GATTACCC", None, spacer).unwrap();
+        assert_eq!((m.id, m.correction), (1, Correction::Substitution(1)));
+
+        // a deleted nucleotide in the barcode
+        let m = barcodes.match_before(b"TTGATTCCC", None, spacer).unwrap();
+        assert_eq!((m.id, m.correction), (1, Correction::Indel));
+
+        assert_eq!(barcodes.match_before(b"CCCCCCCC", None, spacer), None);
+    }
+
+    #[test]
+    fn stagger_lengths() {
+        let stagger = Stagger::new(4, 1, 3).unwrap();
+        assert_eq!(stagger.lengths(b"GTCAGTTCC").collect::<Vec<_>>(), vec![1, 2, 3]);
+        let stagger = stagger.with_onlist(&b"AGT\nGT\nT\n"[..]).unwrap();
+        assert_eq!(stagger.lengths(b"GTCAGTTCC").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(stagger.lengths(b"GTCAAGTCC").collect::<Vec<_>>(), vec![3]);
+        assert_eq!(stagger.lengths(b"GTCACCCCC").count(), 0);
+        assert!(Stagger::new(0, 3, 1).is_err());
+        assert!(Stagger::new(0, 1, 2).unwrap().with_onlist(&b"AGT"[..]).is_err());
+    }
+
+    #[test]
+    fn match_best_placement() {
+        let barcodes = Barcodes::from_buffer(TEST_BUFFER, false).unwrap();
+
+        // a neighbour of GAGAAACC at shift 0, AGAAACCA exactly at shift 1
+        let m = barcodes.match_sequence_with_quality(b"TAGAAACCA", None).unwrap();
+        assert_eq!((m.start, m.id, m.correction, m.ambiguous), (1, 0, Correction::Exact, false));
+
+        // GAGAAACC and AGAAACCA are both exact
+        let m = barcodes.match_sequence_with_quality(b"GAGAAACCA", None).unwrap();
+        assert_eq!((m.start, m.id), (0, 3));
+        assert!(m.ambiguous);
+    }
+
+    /// Compares lookups in the packed index with the byte slice map it replaced.
+    /// Run with `cargo test --release benchmark_packed_index -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn benchmark_packed_index() {
+        use std::time::Instant;
+
+        let spacers = Spacer::from_str(TEST_SPACER);
+        let tolerance = Tolerance::new(false, false);
+        let barcodes = Barcodes::from_file_with_tolerance(TEST_FILE, &spacers, &tolerance).unwrap();
+        let mut map = HashMap::new();
+        for parent in &barcodes.parents {
+            map.insert(parent.seq.clone(), Entry::new(parent.id, Correction::Exact));
+        }
+        Barcodes::insert_substitutions(&mut map, &barcodes.parents, &tolerance);
+        assert_eq!(map.len(), barcodes.map.len());
+
+        // pseudo random reads with a whitelist entry planted in every other read
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let reads = (0..200_000)
+            .map(|i| {
+                let mut read = (0..24)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        NUCLEOTIDES[(state % 4) as usize]
+                    })
+                    .collect::<Vec<_>>();
+                if i % 2 == 0 {
+                    let parent = &barcodes.parents[i % barcodes.parents.len()].seq;
+                    read[3..3 + parent.len()].copy_from_slice(parent);
+                }
+                read
+            })
+            .collect::<Vec<_>>();
+        let len = barcodes.len();
+        let num_windows = reads.len() * (reads[0].len() - len + 1);
+
+        // best of several rounds to reduce noise
+        let time = |lookup: &dyn Fn(&[u8]) -> usize| {
+            (0..5)
+                .map(|_| {
+                    let start = Instant::now();
+                    let hits = reads.iter().map(|read| lookup(read)).sum::<usize>();
+                    (start.elapsed(), hits)
+                })
+                .min()
+                .unwrap()
+        };
+        let (slice_time, slice_hits) = time(&|read| {
+            (0..=read.len() - len)
+                .filter(|&pos| map.contains_key(&read[pos..pos + len]))
+                .count()
+        });
+        let (packed_time, packed_hits) = time(&|read| {
+            PackedWindows::new(read, len)
+                .take(read.len() + 1 - len)
+                .filter_map(|window| window.prefix(len))
+                .filter(|&code| barcodes.map.get_packed(len, code).is_some())
+                .count()
+        });
+
+        assert_eq!(slice_hits, packed_hits);
+        println!(
+            "{} windows, byte slice map: {:.1} ns/window, packed index: {:.1} ns/window ({:.2}x)",
+            num_windows,
+            slice_time.as_nanos() as f64 / num_windows as f64,
+            packed_time.as_nanos() as f64 / num_windows as f64,
+            slice_time.as_secs_f64() / packed_time.as_secs_f64()
+        );
+    }
 }
@@ -0,0 +1,238 @@
+use clap::{
+    builder::{PossibleValue, TypedValueParser},
+    error::ErrorKind,
+    Arg, Command, ValueEnum,
+};
+use serde::Serialize;
+use std::ffi::OsStr;
+
+/// Prefix of the whitelist paths compiled into the binary
+pub const BUILTIN_PREFIX: &str = "builtin:";
+
+/// Whitelists compiled into the binary by their builtin path
+const WHITELISTS: [(&str, &str); 11] = [
+    ("builtin:pipseq-v2/bc1", include_str!("../data/barcodes_v2/pip-seq_v2_bc1.tsv")),
+    ("builtin:pipseq-v2/bc2", include_str!("../data/barcodes_v2/pip-seq_v2_bc2.tsv")),
+    ("builtin:pipseq-v2/bc3", include_str!("../data/barcodes_v2/pip-seq_v2_bc3.tsv")),
+    ("builtin:pipseq-v3/bc1", include_str!("../data/barcodes_v3/fb_v3_bc1.tsv")),
+    ("builtin:pipseq-v3/bc2", include_str!("../data/barcodes_v3/fb_v3_bc2.tsv")),
+    ("builtin:pipseq-v3/bc3", include_str!("../data/barcodes_v3/fb_v3_bc3.tsv")),
+    ("builtin:pipseq-v3/bc4", include_str!("../data/barcodes_v3/fb_v3_bc4.tsv")),
+    ("builtin:vbcf/bc1", include_str!("../configs/20260521/bc1_custom_primer.txt")),
+    ("builtin:vbcf/bc2", include_str!("../configs/20260521/bc2.txt")),
+    ("builtin:vbcf/bc3", include_str!("../configs/20260521/bc3.txt")),
+    ("builtin:vbcf/phase_block", include_str!("../data/vbcf_phase_block.txt")),
+];
+
+/// Chemistries without a preset yet, with the reason
+const UNSUPPORTED: [(&str, &str); 1] = [(
+    "pipseq-v4",
+    "PIPseq v4 has no built-in preset yet, as its whitelists are not distributed with pipspeak. \
+    Use a config with the v4 whitelists instead",
+)];
+
+const PIPSEQ_V2: &str = r#"
+barcodes:
+  bc1: { path: "builtin:pipseq-v2/bc1" }
+  bc2: { path: "builtin:pipseq-v2/bc2" }
+  bc3: { path: "builtin:pipseq-v2/bc3" }
+spacers:
+  s1: { seq: "ATGCATC,ATGCATG" }
+  s2: { seq: "GCTCGAG,CCTCGAG" }
+parameters:
+  umi_len: 12
+  offset: 5
+  umi_offset: 0
+"#;
+
+const PIPSEQ_V3: &str = r#"
+barcodes:
+  bc1: { path: "builtin:pipseq-v3/bc1" }
+  bc2: { path: "builtin:pipseq-v3/bc2" }
+  bc3: { path: "builtin:pipseq-v3/bc3" }
+  bc4: { path: "builtin:pipseq-v3/bc4" }
+spacers:
+  s1: { seq: "ATG" }
+  s2: { seq: "GAG" }
+  s3: { seq: "TCGAG" }
+parameters:
+  umi_len: 12
+  offset: 5
+  umi_offset: 0
+"#;
+
+const VBCF: &str = r#"
+barcodes:
+  bc1: { path: "builtin:vbcf/bc1" }
+  bc2: { path: "builtin:vbcf/bc2" }
+  bc3: { path: "builtin:vbcf/bc3" }
+spacers:
+  s1: { seq: "AACC" }
+  s2: { seq: "ACAG" }
+  s3: { seq: "CCTA" }
+umi_anchor: { seq: "TTCGAG" }
+stagger:
+  start: 4
+  min_len: 1
+  max_len: 3
+  onlist: "builtin:vbcf/phase_block"
+parameters:
+  # the UMI directly follows the UMI anchor
+  umi_len: 14
+"#;
+
+/// Chemistries whose construct is compiled into the binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Chemistry {
+    /// PIPseq v2: three rounds with ambiguous spacers
+    PipseqV2,
+    /// PIPseq v3: four rounds
+    PipseqV3,
+    /// VBCF split-pool: phase block, three rounds and a UMI anchor (seqspec 20260521)
+    Vbcf,
+}
+
+impl Chemistry {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PipseqV2 => "pipseq-v2",
+            Self::PipseqV3 => "pipseq-v3",
+            Self::Vbcf => "vbcf",
+        }
+    }
+
+    /// Version of the preset, changed whenever its whitelists or construct change
+    pub fn version(&self) -> &'static str {
+        match self {
+            Self::PipseqV2 => "2",
+            Self::PipseqV3 => "3",
+            Self::Vbcf => "20260521",
+        }
+    }
+
+    /// Returns the config yaml of the preset
+    pub fn yaml(&self) -> &'static str {
+        match self {
+            Self::PipseqV2 => PIPSEQ_V2,
+            Self::PipseqV3 => PIPSEQ_V3,
+            Self::Vbcf => VBCF,
+        }
+    }
+}
+
+/// Returns why a chemistry has no preset, if it is a known one
+pub fn unsupported(name: &str) -> Option<&'static str> {
+    UNSUPPORTED
+        .iter()
+        .find(|(unsupported, _)| unsupported.eq_ignore_ascii_case(name))
+        .map(|(_, reason)| *reason)
+}
+
+/// Parses `--chemistry`, explaining the chemistries without a preset
+#[derive(Debug, Clone, Copy)]
+pub struct ChemistryParser;
+
+impl TypedValueParser for ChemistryParser {
+    type Value = Chemistry;
+
+    fn parse_ref(&self, cmd: &Command, arg: Option<&Arg>, value: &OsStr) -> Result<Chemistry, clap::Error> {
+        if let Some(reason) = value.to_str().and_then(unsupported) {
+            return Err(clap::Error::raw(ErrorKind::InvalidValue, format!("{}\n", reason)).with_cmd(cmd));
+        }
+        clap::builder::EnumValueParser::<Chemistry>::new().parse_ref(cmd, arg, value)
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(Chemistry::value_variants().iter().filter_map(ValueEnum::to_possible_value)))
+    }
+}
+
+/// The chemistry preset of a run for the log
+#[derive(Debug, Serialize)]
+pub struct ChemistryInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+impl From<Chemistry> for ChemistryInfo {
+    fn from(chemistry: Chemistry) -> Self {
+        Self {
+            name: chemistry.name(),
+            version: chemistry.version(),
+        }
+    }
+}
+
+/// Returns true for paths of whitelists compiled into the binary
+pub fn is_builtin(path: &str) -> bool {
+    path.starts_with(BUILTIN_PREFIX)
+}
+
+/// Returns the contents of a whitelist compiled into the binary
+pub fn builtin_whitelist(path: &str) -> Option<&'static str> {
+    WHITELISTS
+        .iter()
+        .find(|(builtin, _)| *builtin == path)
+        .map(|(_, contents)| *contents)
+}
+
+/// Merges a config over a preset: mappings are merged key by key,
+/// everything else is replaced
+pub fn merge(base: &mut serde_yaml::Value, overrides: serde_yaml::Value) {
+    match (base, overrides) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn builtin_whitelists() {
+        for chemistry in Chemistry::value_variants() {
+            let yaml = serde_yaml::from_str::<serde_yaml::Value>(chemistry.yaml()).unwrap();
+            for (_, round) in yaml["barcodes"].as_mapping().unwrap() {
+                let path = round["path"].as_str().unwrap();
+                assert!(is_builtin(path));
+                assert!(builtin_whitelist(path).is_some(), "{} is not compiled in", path);
+            }
+        }
+        // v4 is requested, but its whitelists are missing
+        assert!(builtin_whitelist("builtin:pipseq-v4/bc1").is_none());
+    }
+
+    #[test]
+    fn unsupported_chemistry() {
+        assert!(unsupported("PIPseq-v4").unwrap().contains("no built-in preset"));
+        assert!(unsupported("pipseq-v3").is_none());
+        let command = Command::new("pipspeak");
+        let error = ChemistryParser.parse_ref(&command, None, OsStr::new("pipseq-v4")).unwrap_err();
+        assert!(error.to_string().contains("v4 whitelists"));
+        let chemistry = ChemistryParser.parse_ref(&command, None, OsStr::new("vbcf")).unwrap();
+        assert_eq!(chemistry, Chemistry::Vbcf);
+        assert!(ChemistryParser.parse_ref(&command, None, OsStr::new("pipseq-v5")).is_err());
+    }
+
+    #[test]
+    fn merge_configs() {
+        let mut base = serde_yaml::from_str::<serde_yaml::Value>(PIPSEQ_V3).unwrap();
+        let overrides = serde_yaml::from_str("barcodes: {bc2: {mismatches: 0}, bc4: bc4.tsv}\nparameters: {umi_len: 10}").unwrap();
+        merge(&mut base, overrides);
+        assert_eq!(base["barcodes"]["bc2"]["path"].as_str(), Some("builtin:pipseq-v3/bc2"));
+        assert_eq!(base["barcodes"]["bc2"]["mismatches"].as_u64(), Some(0));
+        assert_eq!(base["barcodes"]["bc4"].as_str(), Some("bc4.tsv"));
+        assert_eq!(base["parameters"]["umi_len"].as_u64(), Some(10));
+        assert_eq!(base["parameters"]["offset"].as_u64(), Some(5));
+    }
+}
@@ -1,53 +1,161 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+
+use crate::chemistry::{Chemistry, ChemistryParser};
+use crate::orientation::Orientation;
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Input file for R1
-    #[clap(short = 'i', long, value_parser)]
-    pub r1: String,
+    #[clap(short = 'i', long, value_parser, required = true)]
+    pub r1: Option<String>,
 
     /// Input file for R2
-    #[clap(short = 'I', long, value_parser)]
-    pub r2: String,
+    #[clap(short = 'I', long, value_parser, required = true)]
+    pub r2: Option<String>,
 
     /// Output file prefix (output files will be named <prefix>_R[12].fq.gz)
     #[clap(short = 'p', long, value_parser, default_value = "pipspeak")]
     pub prefix: String,
 
-    /// Number of threads to use in gzip compression (0 = all threads)
+    /// Number of threads to use, half matching barcodes and half compressing R1 and R2 (0 = all threads)
     #[clap(short = 't', long, default_value = "1")]
     pub threads: usize,
 
-    /// The amount of nucleotides away from the start of R1 to accept a barcode
-    #[clap(short = 's', long, default_value = "5")]
-    pub offset: usize,
+    /// Write reads as soon as their batch is matched instead of in input order
+    #[clap(long)]
+    pub unordered: bool,
+
+    /// The amount of nucleotides away from the start of R1 to accept a barcode [default: 5]
+    #[clap(short = 's', long)]
+    pub offset: Option<usize>,
+
+    /// The yaml config file describing the file paths of the 4 barcodes and the spacers.
+    /// With --chemistry, its fields override those of the preset
+    #[clap(short = 'c', long, value_parser, required_unless_present_any = ["read_structure", "seqspec", "chemistry", "detect"])]
+    pub config: Option<String>,
+
+    /// Built-in chemistry preset with its whitelists, spacers, UMI length and offsets
+    #[clap(long, value_parser = ChemistryParser, conflicts_with_all = ["read_structure", "seqspec"])]
+    pub chemistry: Option<Chemistry>,
+
+    /// Detect the chemistry by matching the first reads against every preset
+    /// and --candidate config, then continue with the best one
+    #[clap(long, conflicts_with_all = ["config", "chemistry", "read_structure", "seqspec"])]
+    pub detect: bool,
+
+    /// Stop after printing the detection table
+    #[clap(long, requires = "detect")]
+    pub detect_only: bool,
 
-    /// The yaml config file describing the file paths of the 4 barcodes and the spacers
-    #[clap(short = 'c', long, value_parser)]
-    pub config: String,
+    /// Config tried in detection in addition to the presets (repeatable)
+    #[clap(long, requires = "detect")]
+    pub candidate: Vec<String>,
 
-    /// The length of the UMI, overriden in the config file if present
-    #[clap(short = 'u', long, default_value = "12")]
-    pub umi_len: usize,
+    /// Number of read pairs used to detect the chemistry
+    #[clap(long, default_value = "100000")]
+    pub detect_sample: usize,
 
-    /// Offset of the UMI from the last base of the barcode (0 = no offset)
-    #[clap(long, default_value = "0")]
-    pub umi_offset: usize,
+    /// Read structure of R1 as an alternative to the config,
+    /// e.g. "8B[bc1] 3S[ATG] 6B[bc2] 3S[GAG] 6B[bc3] 5S[TCGAG] 8B[bc4] 12U".
+    /// Sets the UMI length and offset
+    #[clap(short = 'r', long, conflicts_with_all = ["config", "seqspec"], requires = "onlist")]
+    pub read_structure: Option<String>,
+
+    /// Seqspec yaml describing the construct as an alternative to the config.
+    /// Sets the UMI length and offset
+    #[clap(long, conflicts_with = "config")]
+    pub seqspec: Option<String>,
+
+    /// Onlist of a barcode segment of the read structure, or replacing the onlist
+    /// of a seqspec region, as NAME=PATH (repeatable)
+    #[clap(long)]
+    pub onlist: Vec<String>,
+
+    /// The length of the UMI [default: 12]
+    #[clap(short = 'u', long)]
+    pub umi_len: Option<usize>,
+
+    /// Offset of the UMI from the last base of the barcode (0 = no offset) [default: 0]
+    #[clap(long)]
+    pub umi_offset: Option<usize>,
 
     /// Use exact matching instead of one mismatch
-    #[clap(short = 'x', long)]
-    pub exact: bool,
+    #[clap(short = 'x', long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub exact: Option<bool>,
+
+    /// Also correct a single insertion or deletion in barcodes and spacers
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub indels: Option<bool>,
+
+    /// Resolve barcodes that are one mismatch away from several whitelist entries
+    /// by the R1 base qualities, accepting the most likely entry if its
+    /// posterior probability is at least this value (e.g. 0.95)
+    #[clap(long)]
+    pub resolve_ambiguous: Option<f64>,
+
+    /// Maximum number of N bases in a barcode that are treated as wildcards,
+    /// matching if only one whitelist entry is compatible (0 = N is a mismatch) [default: 0]
+    #[clap(long)]
+    pub max_n: Option<usize>,
+
+    /// Locate each round by the spacer following it and the UMI by the
+    /// `umi_anchor` of the config, instead of matching from the start of R1
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub anchored: Option<bool>,
+
+    /// Which read carries the barcodes and in which direction.
+    /// Detected from the first reads if not given
+    #[clap(long, value_enum)]
+    pub orientation: Option<Orientation>,
+
+    /// Number of read pairs used to detect the orientation [default: 1000]
+    #[clap(long)]
+    pub orientation_sample: Option<usize>,
+
+    /// Also match the reverse complement of reads whose barcodes are not found,
+    /// for libraries with mixed orientation
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub mixed_orientation: Option<bool>,
 
     /// Include linkers in the output
-    #[clap(short = 'l', long)]
-    pub linkers: bool,
+    #[clap(short = 'l', long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub linkers: Option<bool>,
 
     /// Do not write anything to stderr
     #[clap(short = 'q', long)]
     pub quiet: bool,
 
     /// Log level
-    #[clap(short = 'e', long, default_value = "info")]
+    #[clap(short = 'e', long, default_value = "info", global = true)]
     pub loglevel: String,
 }
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Check a config and its whitelists without processing reads.
+    /// Exits with a non-zero status if problems are found
+    Validate(ValidateArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ValidateArgs {
+    /// The yaml config file to check. With --chemistry, its fields override those of the preset
+    #[clap(short = 'c', long, required_unless_present = "chemistry")]
+    pub config: Option<String>,
+
+    /// Built-in chemistry preset to check
+    #[clap(long, value_parser = ChemistryParser)]
+    pub chemistry: Option<Chemistry>,
+
+    /// Check for exact matching instead of one mismatch
+    #[clap(short = 'x', long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub exact: Option<bool>,
+
+    /// Also exit with a non-zero status on warnings
+    #[clap(long)]
+    pub strict: bool,
+}
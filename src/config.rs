@@ -1,94 +1,536 @@
-use crate::barcodes::{Barcodes, Spacer};
-use anyhow::Result;
+use crate::barcodes::{best_match, Anchor, BarcodeMatch, Barcodes, Correction, Spacer, Stagger, Tolerance};
+use crate::chemistry::{self, Chemistry};
+use crate::orientation::Orientation;
+use crate::read_structure::{ReadStructure, Round};
+use crate::paths;
+use crate::seqspec::{Construct, StaggerRegion};
+use crate::whitelist::{self, Column, Format};
+use anyhow::{Context, Result};
 use serde::Deserialize;
 use indexmap::IndexMap;
-use log::info;
+use log::{info, warn};
+use std::path::Path;
 
 
 #[derive(Debug, Deserialize)]
 pub struct ConfigYamlRead {
-    barcodes: IndexMap<String, String>,
-    spacers: IndexMap<String, String>,
+    barcodes: IndexMap<String, BarcodeEntry>,
+    spacers: IndexMap<String, SpacerEntry>,
+    umi_anchor: Option<SpacerEntry>,
+    stagger: Option<StaggerSegment>,
     parameters: Option<ConfigParameters>,
 }
 
 
 #[derive(Debug, Deserialize)]
 pub struct ConfigYaml {
-    barcodes: Vec<String>,
-    spacers: Vec<String>,
+    barcodes: Vec<BarcodeRound>,
+    spacers: Vec<SpacerRound>,
+    /// Constant sequence directly preceding the UMI
+    umi_anchor: Option<SpacerRound>,
+    /// Variable length segment in front of bc1
+    stagger: Option<StaggerSegment>,
     parameters: Option<ConfigParameters>,
 }
 
 
-#[derive(Debug, Deserialize)]
+/// A barcode round of a config before its whitelist is loaded
+pub struct RoundSpec {
+    pub path: String,
+    pub format: Format,
+    pub spacers: Vec<Spacer>,
+    pub tolerance: Tolerance,
+}
+
+impl ConfigYaml {
+    /// Reads a config, resolving the paths in it against the directory of the config
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read the config {}", path))?;
+        let read_yaml = serde_yaml::from_str::<ConfigYamlRead>(&contents)?;
+        Self::from_read_yaml(read_yaml, paths::parent_dir(path))
+    }
+
+    /// Reads a built-in chemistry, with the fields of an optional config
+    /// replacing those of the preset
+    pub fn from_preset(chemistry: Chemistry, overrides: Option<&str>) -> Result<Self> {
+        let mut preset = serde_yaml::from_str::<serde_yaml::Value>(chemistry.yaml())?;
+        if let Some(path) = overrides {
+            let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read the config {}", path))?;
+            let overrides = serde_yaml::from_str::<serde_yaml::Value>(&contents)
+                .with_context(|| format!("Failed to parse the config {}", path))?;
+            // an empty config overrides nothing
+            if !overrides.is_null() {
+                chemistry::merge(&mut preset, overrides);
+            }
+        }
+        let read_yaml = serde_yaml::from_value::<ConfigYamlRead>(preset)
+            .with_context(|| format!("Invalid config for the {} preset", chemistry.name()))?;
+        let dir = overrides.map_or(Path::new(""), paths::parent_dir);
+        Self::from_read_yaml(read_yaml, dir)
+    }
+
+    /// Builds the config from a parsed yaml, resolving its paths against `dir`
+    fn from_read_yaml(read_yaml: ConfigYamlRead, dir: &Path) -> Result<Self> {
+        let mut stagger = read_yaml.stagger;
+        if let Some(segment) = stagger.as_mut() {
+            segment.onlist = segment.onlist.as_deref().map(|p| resolve_config_path(p, dir)).transpose()?;
+        }
+        Ok(Self {
+            barcodes: read_yaml
+                .barcodes
+                .into_values()
+                .map(BarcodeEntry::into_round)
+                .map(|round| Ok(BarcodeRound { path: resolve_config_path(&round.path, dir)?, ..round }))
+                .collect::<Result<_>>()?,
+            spacers: read_yaml
+                .spacers
+                .into_values()
+                .map(SpacerEntry::into_spacer)
+                .collect(),
+            umi_anchor: read_yaml.umi_anchor.map(SpacerEntry::into_spacer),
+            stagger,
+            parameters: read_yaml.parameters,
+        })
+    }
+
+    /// Returns the whitelist path, spacer variants and effective tolerance of each round
+    pub fn rounds(&self, tolerance: &Tolerance) -> Vec<RoundSpec> {
+        self.barcodes
+            .iter()
+            .enumerate()
+            .map(|(idx, round)| {
+                let spacer = self.spacers.get(idx);
+                RoundSpec {
+                    path: round.path.clone(),
+                    format: round.format(),
+                    spacers: spacer.map(|s| Spacer::from_str(&s.seq)).unwrap_or_default(),
+                    tolerance: tolerance.for_round(round.mismatches, spacer.and_then(|s| s.mismatches)),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the run parameters set in the config.
+    /// The `max_shift` of bc1 is its `offset`, taking precedence over `parameters`
+    pub fn parameters(&self) -> ConfigParameters {
+        let mut parameters = self.parameters.clone().unwrap_or_default();
+        if let Some(max_shift) = self.barcodes.first().and_then(|round| round.max_shift) {
+            parameters.offset = Some(max_shift);
+        }
+        parameters
+    }
+
+    /// Returns the variants of the UMI anchor
+    pub fn umi_anchor(&self) -> Vec<Spacer> {
+        self.umi_anchor.as_ref().map(|s| Spacer::from_str(&s.seq)).unwrap_or_default()
+    }
+}
+
+
+/// A barcode round is either given as the path of its whitelist
+/// or as a mapping with round specific settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BarcodeEntry {
+    Path(String),
+    Round(BarcodeRound),
+}
+impl BarcodeEntry {
+    pub fn into_round(self) -> BarcodeRound {
+        match self {
+            Self::Path(path) => BarcodeRound {
+                path,
+                ..Default::default()
+            },
+            Self::Round(round) => round,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BarcodeRound {
+    path: String,
+    /// Maximum hamming distance corrected in this round
+    mismatches: Option<usize>,
+    /// Nucleotides the round is shifted at least from the end of the previous segment
+    min_shift: Option<usize>,
+    /// Nucleotides the round may be shifted at most from the end of the previous segment
+    max_shift: Option<usize>,
+    /// Column of the whitelist table holding the sequences
+    sequence_column: Option<Column>,
+    /// Column of the whitelist table holding the entry names (e.g. wells)
+    name_column: Option<Column>,
+    /// Whether the whitelist table starts with a header
+    header: Option<bool>,
+}
+
+impl BarcodeRound {
+    /// Returns the layout of the whitelist
+    fn format(&self) -> Format {
+        Format {
+            sequence_column: self.sequence_column.clone(),
+            name_column: self.name_column.clone(),
+            header: self.header,
+        }
+    }
+}
+
+
+/// A spacer is either given as its sequence (comma separated variants)
+/// or as a mapping with its own mismatch budget
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SpacerEntry {
+    Sequence(String),
+    Spacer(SpacerRound),
+}
+impl SpacerEntry {
+    pub fn into_spacer(self) -> SpacerRound {
+        match self {
+            Self::Sequence(seq) => SpacerRound {
+                seq,
+                mismatches: None,
+            },
+            Self::Spacer(spacer) => spacer,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpacerRound {
+    seq: String,
+    /// Maximum hamming distance corrected in the spacer,
+    /// independent of the barcode budget
+    mismatches: Option<usize>,
+}
+
+
+/// A variable length segment (e.g. a phase block) in front of the first barcode
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaggerSegment {
+    /// Position of the segment in the read
+    #[serde(default)]
+    start: usize,
+    min_len: usize,
+    max_len: usize,
+    /// Path of the allowed segment sequences
+    onlist: Option<String>,
+}
+
+
+/// Run parameters set in the config, overridden on the command line
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigParameters {
-    umi_len: usize,
+    pub umi_len: Option<usize>,
+    /// Nucleotides away from the start of R1 to accept bc1
+    pub offset: Option<usize>,
+    /// Offset of the UMI from the last base of the barcode
+    pub umi_offset: Option<usize>,
+    pub exact: Option<bool>,
+    pub indels: Option<bool>,
+    pub resolve_ambiguous: Option<f64>,
+    pub max_n: Option<usize>,
+    pub anchored: Option<bool>,
+    pub orientation: Option<Orientation>,
+    pub orientation_sample: Option<usize>,
+    pub mixed_orientation: Option<bool>,
+    pub linkers: Option<bool>,
 }
 
 pub struct Config {
     barcodes: Vec<Barcodes>,
+    /// Whitelist path of each round
+    paths: Vec<String>,
+    /// Allowed minimum and maximum shift of each round, if set in the config
+    windows: Vec<(usize, Option<usize>)>,
+    /// The spacer of each round as an anchor for anchor-first parsing
+    anchors: Vec<Option<Anchor>>,
+    umi_anchor: Option<Anchor>,
+    stagger: Option<Stagger>,
+    /// The stagger segment as given, to describe the construct
+    stagger_segment: Option<StaggerSegment>,
+    anchored: bool,
     linkers: bool,
-    umi_len: usize,
+    parameters: ConfigParameters,
 }
 
 
+/// Resolves a path of a config against the directory of the config
+fn resolve_config_path(path: &str, dir: &Path) -> Result<String> {
+    if chemistry::is_builtin(path) {
+        return Ok(path.to_string());
+    }
+    let mut resolved = paths::resolve(path, dir)?;
+    // paths used to be resolved against the working directory
+    if !resolved.exists() {
+        let legacy = paths::resolve(path, Path::new(""))?;
+        if legacy.exists() {
+            warn!(
+                "{} is not found next to the config, using {} in the working directory",
+                path,
+                legacy.display()
+            );
+            resolved = legacy;
+        }
+    }
+    Ok(resolved.to_string_lossy().into_owned())
+}
+
 impl Config {
+    #[allow(dead_code)]
     pub fn from_file(path: &str, exact: bool, linkers: bool) -> Result<Self> {
-        let contents = std::fs::read_to_string(path)?;
-        let read_yaml = serde_yaml::from_str::<ConfigYamlRead>(&contents)?;
+        Self::from_file_with_tolerance(path, &Tolerance::new(exact, false), linkers)
+    }
+
+    /// Loads a config, resolving the paths in it against the directory of the config
+    pub fn from_file_with_tolerance(path: &str, tolerance: &Tolerance, linkers: bool) -> Result<Self> {
+        Self::from_yaml(ConfigYaml::from_file(path)?, tolerance, linkers)
+    }
+
+    /// Loads a built-in chemistry, with the fields of an optional config
+    /// replacing those of the preset
+    #[allow(dead_code)]
+    pub fn from_preset(
+        chemistry: Chemistry,
+        overrides: Option<&str>,
+        tolerance: &Tolerance,
+        linkers: bool,
+    ) -> Result<Self> {
+        Self::from_yaml(ConfigYaml::from_preset(chemistry, overrides)?, tolerance, linkers)
+    }
+
+    /// Builds the config from a read structure, with the onlist of each
+    /// barcode segment given by its name
+    pub fn from_read_structure(
+        structure: &ReadStructure,
+        onlists: &IndexMap<String, String>,
+        tolerance: &Tolerance,
+        linkers: bool,
+    ) -> Result<Self> {
+        Self::from_rounds(
+            &structure.rounds,
+            structure.umi_anchor.as_deref(),
+            None,
+            structure.umi_len,
+            structure.umi_offset,
+            onlists,
+            tolerance,
+            linkers,
+        )
+    }
+
+    /// Builds the config from the barcode read of a seqspec. `onlists`
+    /// replace the onlists of the seqspec by region id
+    pub fn from_seqspec(
+        construct: &Construct,
+        onlists: &IndexMap<String, String>,
+        tolerance: &Tolerance,
+        linkers: bool,
+    ) -> Result<Self> {
+        let mut construct_onlists = construct.onlists.clone();
+        construct_onlists.extend(onlists.iter().map(|(name, path)| (name.clone(), path.clone())));
+        let stagger = construct.stagger.as_ref().map(|region| StaggerSegment {
+            start: region.start,
+            min_len: region.min_len,
+            max_len: region.max_len,
+            onlist: onlists.get(&region.name).or(region.onlist.as_ref()).cloned(),
+        });
+        Self::from_rounds(
+            &construct.rounds,
+            construct.umi_anchor.as_deref(),
+            stagger,
+            construct.umi_len,
+            construct.umi_offset,
+            &construct_onlists,
+            tolerance,
+            linkers,
+        )
+    }
+
+    /// Builds the config from barcode rounds with the onlist of each round given by its name
+    #[allow(clippy::too_many_arguments)]
+    fn from_rounds(
+        rounds: &[Round],
+        umi_anchor: Option<&str>,
+        stagger: Option<StaggerSegment>,
+        umi_len: usize,
+        umi_offset: usize,
+        onlists: &IndexMap<String, String>,
+        tolerance: &Tolerance,
+        linkers: bool,
+    ) -> Result<Self> {
+        let barcodes = rounds
+            .iter()
+            .map(|round| {
+                let path = onlists
+                    .get(&round.name)
+                    .with_context(|| format!("No onlist given for barcode '{}'", round.name))?;
+                Ok(BarcodeRound {
+                    path: path.clone(),
+                    min_shift: round.shift.map(|(min, _)| min),
+                    max_shift: round.shift.map(|(_, max)| max),
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let spacers = rounds
+            .iter()
+            .map_while(|round| round.spacer.clone())
+            .map(|seq| SpacerRound { seq, mismatches: None })
+            .collect();
         let yaml = ConfigYaml {
-            barcodes: read_yaml.barcodes.values().cloned().collect(),
-            spacers: read_yaml.spacers.values().cloned().collect(),
-            parameters: read_yaml.parameters,
+            barcodes,
+            spacers,
+            umi_anchor: umi_anchor.map(|seq| SpacerRound { seq: seq.to_string(), mismatches: None }),
+            stagger,
+            parameters: Some(ConfigParameters {
+                umi_len: Some(umi_len),
+                umi_offset: Some(umi_offset),
+                ..Default::default()
+            }),
         };
-        Self::from_yaml(yaml, exact, linkers)
+        let config = Self::from_yaml(yaml, tolerance, linkers)?;
+
+        for (idx, (round, barcodes)) in rounds.iter().zip(&config.barcodes).enumerate() {
+            let spacer_len = barcodes.spacer_len();
+            let (min_len, max_len) = (barcodes.min_len() - spacer_len, barcodes.len() - spacer_len);
+            if min_len < round.min_len || max_len > round.max_len {
+                anyhow::bail!(
+                    "bc{} '{}' has barcodes of {} to {} nt, which don't fit its segment of {} to {} nt",
+                    idx + 1, round.name, min_len, max_len, round.min_len, round.max_len
+                );
+            }
+        }
+        Ok(config)
     }
 
-    pub fn from_yaml(yaml: ConfigYaml, exact: bool, linkers: bool) -> Result<Self> {
+    pub fn from_yaml(yaml: ConfigYaml, tolerance: &Tolerance, linkers: bool) -> Result<Self> {
+        let parameters = yaml.parameters();
         let mut barcodes = Vec::new();
-        for (idx, barcode_path) in yaml.barcodes.iter().enumerate() {
-            let spacers = yaml.spacers.get(idx).map(|s| Spacer::from_str(s)).unwrap_or_else(Vec::new);
-            let barcode = Self::load_barcode(barcode_path, &spacers, exact)?;
+        let mut windows = Vec::new();
+        let mut anchors = Vec::new();
+        for (idx, round) in yaml.barcodes.iter().enumerate() {
+            let spacer = yaml.spacers.get(idx);
+            let spacers = spacer.map(|s| Spacer::from_str(&s.seq)).unwrap_or_else(Vec::new);
+            let round_tolerance = tolerance.for_round(round.mismatches, spacer.and_then(|s| s.mismatches));
+            let barcode = whitelist::open(&round.path)
+                .and_then(|reader| whitelist::read_entries(reader, &round.format()))
+                .and_then(|entries| Barcodes::from_entries(&entries, &spacers, &round_tolerance))
+                .with_context(|| format!("Failed to load bc{} from {}", idx + 1, round.path))?;
             info!("barcodes:\n{}", barcode.to_str());
             barcodes.push(barcode);
+
+            let min_shift = round.min_shift.unwrap_or(0);
+            if round.max_shift.is_some_and(|max_shift| max_shift < min_shift) {
+                anyhow::bail!("bc{} max_shift is smaller than its min_shift", idx + 1);
+            }
+            windows.push((min_shift, round.max_shift));
+
+            // spacers are located with their own budget, or a single mismatch
+            let anchor_mismatches = round_tolerance
+                .spacer_mismatches
+                .unwrap_or(round_tolerance.mismatches.min(1));
+            anchors.push(spacer.map(|s| Anchor::new(Spacer::from_str(&s.seq), anchor_mismatches)));
+        }
+
+        let umi_anchor = yaml.umi_anchor.map(|s| {
+            let mismatches = if tolerance.mismatches == 0 {
+                0
+            } else {
+                s.mismatches.unwrap_or(1)
+            };
+            Anchor::new(Spacer::from_str(&s.seq), mismatches)
+        });
+
+        let stagger_segment = yaml.stagger.clone();
+        let stagger = match yaml.stagger {
+            Some(segment) => {
+                let stagger = Stagger::new(segment.start, segment.min_len, segment.max_len)?;
+                Some(match &segment.onlist {
+                    Some(path) => whitelist::open(path)
+                        .and_then(|reader| stagger.with_onlist(reader))
+                        .with_context(|| format!("Failed to load the stagger onlist from {}", path))?,
+                    None => stagger,
+                })
+            }
+            None => None,
+        };
+
+        // combination ids are mixed radix numbers over the whitelist sizes
+        if barcodes
+            .iter()
+            .try_fold(1u128, |product, bc| product.checked_mul(bc.num_entries() as u128))
+            .is_none()
+        {
+            anyhow::bail!("The number of barcode combinations exceeds {}", u128::MAX);
         }
 
-        let umi_len = yaml.parameters.map(|p| p.umi_len).unwrap_or(0);
 
         Ok(Self {
             barcodes,
+            paths: yaml.barcodes.into_iter().map(|round| round.path).collect(),
+            windows,
+            anchors,
+            umi_anchor,
+            stagger,
+            stagger_segment,
+            anchored: false,
             linkers,
-            umi_len,
+            parameters,
         })
     }
 
+    /// Enables anchor-first parsing: rounds are located by the spacer
+    /// following them and the UMI by the UMI anchor
+    pub fn with_anchored(mut self, anchored: bool) -> Self {
+        self.anchored = anchored;
+        self
+    }
+
     pub fn build_barcode(&self, indices: &[usize]) -> Vec<u8> {
         let mut bc = Vec::new();
         for (idx, &barcode_idx) in indices.iter().enumerate() {
             bc.extend_from_slice(
                 self.barcodes[idx]
                     .get_barcode(barcode_idx, self.linkers)
-                    .expect(&format!("Invalid barcode index in bc{}", idx + 1)),
+                    .unwrap_or_else(|| panic!("Invalid barcode index in bc{}", idx + 1)),
             );
         }
         bc
     }
 
+    /// Returns a unique number for a combination of barcode indices,
+    /// with the first round as the most significant digit
+    pub fn combination_id(&self, indices: &[usize]) -> u128 {
+        indices
+            .iter()
+            .zip(&self.barcodes)
+            .fold(0, |id, (&idx, bc)| id * bc.num_entries() as u128 + idx as u128)
+    }
+
     pub fn barcode_count(&self) -> usize {
         self.barcodes.len()
     }
-   
-    fn load_barcode(path: &str, spacers: &Vec<Spacer>, exact: bool) -> Result<Barcodes> {
-        if spacers.len() > 0 {
-            Barcodes::from_file_with_spacer(path, spacers, exact)
-        } else {
-            Barcodes::from_file(path, exact)
-        }
+
+    /// Returns the effective mismatch budget of each round
+    pub fn mismatches(&self) -> Vec<usize> {
+        self.barcodes.iter().map(|bc| bc.mismatches()).collect()
     }
 
+    /// Returns the separate spacer mismatch budget of each round
+    pub fn spacer_mismatches(&self) -> Vec<Option<usize>> {
+        self.barcodes.iter().map(|bc| bc.spacer_mismatches()).collect()
+    }
+   
     /// Matches a subsequence starting from `pos` against one of the barcode sets.
     /// Returns the end nucleotide position of the match and the within-set barcode index
+    #[allow(dead_code)]
     pub fn match_subsequence(
         &self,
         seq: &[u8],
@@ -96,21 +538,226 @@ impl Config {
         pos: usize,
         offset: Option<usize>,
     ) -> Option<(usize, usize)> {
+        self.match_subsequence_with_quality(seq, None, set_idx, pos, offset)
+            .map(|m| (m.end, m.id))
+    }
+
+    /// Matches a subsequence and its qualities starting from `pos` against one of the barcode sets.
+    /// The end position of the match is relative to `pos`
+    pub fn match_subsequence_with_quality(
+        &self,
+        seq: &[u8],
+        qual: Option<&[u8]>,
+        set_idx: usize,
+        pos: usize,
+        offset: Option<usize>,
+    ) -> Option<BarcodeMatch> {
         let bc = match self.barcodes.get(set_idx){
             Some(bc) => bc,
             None => panic!("Invalid set index: {}", set_idx),
         };
-        if let Some(off) = offset {
-            bc.match_subsequence(seq, pos, pos + bc.len() + off)
+        let end = pos + bc.len() + offset.unwrap_or(0);
+        bc.match_subsequence_with_quality(seq, qual, pos, end)
+    }
+
+
+    /// Returns the minimum and maximum shift of a round.
+    /// The maximum falls back to `default_max_shift` if not set in the config,
+    /// bc1 takes the resolved offset instead (see `round_window`)
+    pub fn window(&self, set_idx: usize, default_max_shift: usize) -> (usize, usize) {
+        let (min_shift, max_shift) = self.windows[set_idx];
+        (min_shift, max_shift.unwrap_or(default_max_shift).max(min_shift))
+    }
+
+    /// Matches a round shifted by `min_shift` to `max_shift` nucleotides from `pos`,
+    /// locating it by its anchor in anchored mode.
+    /// The positions of the match are relative to `pos`
+    pub fn match_round(
+        &self,
+        seq: &[u8],
+        qual: Option<&[u8]>,
+        set_idx: usize,
+        pos: usize,
+        (min_shift, max_shift): (usize, usize),
+    ) -> Option<BarcodeMatch> {
+        if self.anchored && self.round_anchor(set_idx).is_some() {
+            return self.match_anchored(seq, qual, set_idx, pos, (min_shift, max_shift));
+        }
+        let offset = Some(max_shift - min_shift);
+        self.match_subsequence_with_quality(seq, qual, set_idx, pos + min_shift, offset)
+            .map(|m| BarcodeMatch {
+                start: m.start + min_shift,
+                end: m.end + min_shift,
+                ..m
+            })
+    }
+
+    /// Returns true if a stagger segment precedes the first barcode
+    pub fn has_stagger(&self) -> bool {
+        self.stagger.is_some()
+    }
+
+    /// Returns the position of the stagger segment in the read, 0 without one
+    pub fn stagger_start(&self) -> usize {
+        self.stagger.as_ref().map_or(0, |stagger| stagger.start())
+    }
+
+    /// Matches the first barcode directly after the stagger segment.
+    /// Returns the match and the observed stagger length, preferring
+    /// the best match and then the shortest stagger.
+    /// The end of the match is its position in the read, the start
+    /// its shift from the end of the stagger
+    pub fn match_staggered(&self, seq: &[u8], qual: Option<&[u8]>) -> Option<(BarcodeMatch, usize)> {
+        let stagger = self.stagger.as_ref()?;
+        let bc = &self.barcodes[0];
+        best_match(stagger.lengths(seq).filter_map(|len| {
+            let pos = stagger.start() + len;
+            bc.match_subsequence_with_quality(seq, qual, pos, pos + bc.len())
+                .map(|m| (BarcodeMatch { end: pos + m.end, ..m }, len))
+        }))
+    }
+
+    /// Returns the anchor following a round: its spacer or,
+    /// for a last round without spacer, the UMI anchor
+    fn round_anchor(&self, set_idx: usize) -> Option<&Anchor> {
+        match &self.anchors[set_idx] {
+            Some(anchor) => Some(anchor),
+            None if set_idx + 1 == self.barcodes.len() => self.umi_anchor.as_ref(),
+            None => None,
+        }
+    }
+
+    /// Locates the anchor of a round within its search window
+    /// and matches the barcode directly preceding it.
+    /// The positions of the match are relative to `pos`
+    fn match_anchored(
+        &self,
+        seq: &[u8],
+        qual: Option<&[u8]>,
+        set_idx: usize,
+        pos: usize,
+        (min_shift, max_shift): (usize, usize),
+    ) -> Option<BarcodeMatch> {
+        let bc = &self.barcodes[set_idx];
+        let anchor = self.round_anchor(set_idx)?;
+        let is_spacer = self.anchors[set_idx].is_some();
+
+        let spacer_len = bc.spacer_len();
+        let indel = usize::from(bc.indels());
+        let first = pos + min_shift + (bc.min_len() - spacer_len).saturating_sub(indel);
+        let last = pos + max_shift + bc.len() - spacer_len + indel;
+        let hit = anchor.find(seq, first, last)?;
+
+        let spacer = if is_spacer { anchor.seq(hit.variant) } else { b"" };
+        let region = &seq[pos..hit.start];
+        let quality = qual.and_then(|q| q.get(pos..hit.start));
+        let mut m = bc.match_before(region, quality, spacer)?;
+        if m.start < min_shift {
+            return None;
+        }
+        if is_spacer && hit.mismatches > 0 {
+            // the spacer errors are part of the total distance, as in forward matching
+            let spacer_errors = hit.mismatches as u8;
+            m.correction = match m.correction {
+                Correction::Exact => Correction::Substitution(spacer_errors),
+                Correction::Substitution(dist) => Correction::Substitution(dist + spacer_errors),
+                other => other,
+            };
+            m.spacer_errors = spacer_errors;
+        }
+        if is_spacer {
+            m.end = hit.end - pos;
+        }
+        Some(m)
+    }
+
+    /// Returns the length of the UMI anchor, whether or not parsing is anchored
+    pub fn umi_anchor_len(&self) -> Option<usize> {
+        self.umi_anchor.as_ref().map(|anchor| anchor.seq(0).len())
+    }
+
+    /// Returns the UMI anchor if parsing is anchored
+    pub fn umi_anchor(&self) -> Option<&Anchor> {
+        if self.anchored {
+            self.umi_anchor.as_ref()
         } else {
-            bc.match_subsequence(seq, pos, pos + bc.len())
+            None
         }
     }
 
+    /// Describes the barcode read with the search window of each round,
+    /// e.g. to export it as a seqspec
+    pub fn construct(&self, windows: &[(usize, usize)], umi_len: usize, umi_offset: usize) -> Construct {
+        let rounds = self
+            .barcodes
+            .iter()
+            .zip(windows)
+            .enumerate()
+            .map(|(idx, (barcodes, &shift))| {
+                let spacer_len = barcodes.spacer_len();
+                Round {
+                    name: format!("bc{}", idx + 1),
+                    min_len: barcodes.min_len() - spacer_len,
+                    max_len: barcodes.len() - spacer_len,
+                    // the first round follows the stagger directly
+                    shift: (shift != (0, 0) && !(idx == 0 && self.stagger.is_some())).then_some(shift),
+                    spacer: self.anchors[idx]
+                        .as_ref()
+                        .map(|anchor| String::from_utf8_lossy(anchor.seq(0)).into_owned()),
+                }
+            })
+            .collect::<Vec<_>>();
+        let onlists = rounds
+            .iter()
+            .zip(&self.paths)
+            .map(|(round, path)| (round.name.clone(), path.clone()))
+            .collect();
+        let stagger = self.stagger_segment.as_ref().map(|segment| StaggerRegion {
+            name: "stagger".to_string(),
+            start: segment.start,
+            min_len: segment.min_len,
+            max_len: segment.max_len,
+            onlist: segment.onlist.clone(),
+        });
+        let umi_anchor = self
+            .umi_anchor
+            .as_ref()
+            .map(|anchor| String::from_utf8_lossy(anchor.seq(0)).into_owned());
+        Construct {
+            rounds,
+            stagger,
+            // the UMI directly follows its anchor
+            umi_offset: if umi_anchor.is_some() { 0 } else { umi_offset },
+            umi_anchor,
+            umi_len,
+            onlists,
+        }
+    }
 
-    /// Returns the length of the UMI
+    /// Returns the whitelist path of each round
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Returns the onlist path of the stagger segment
+    pub fn stagger_onlist(&self) -> Option<&str> {
+        self.stagger_segment.as_ref().and_then(|segment| segment.onlist.as_deref())
+    }
+
+    /// Returns true if the linkers are part of the output barcode
+    pub fn linkers(&self) -> bool {
+        self.linkers
+    }
+
+    /// Returns the length of the UMI, 0 if not set in the config
+    #[allow(dead_code)]
     pub fn umi_len(&self) -> usize {
-        self.umi_len
+        self.parameters.umi_len.unwrap_or(0)
+    }
+
+    /// Returns the run parameters set in the config
+    pub fn parameters(&self) -> &ConfigParameters {
+        &self.parameters
     }
 
     /// Returns the barcode based on index
@@ -118,13 +765,30 @@ impl Config {
         self.barcodes.get(position).and_then(|bc| bc.get_barcode(b_index, self.linkers))
     }
 
+    /// Returns the name of a barcode (e.g. its well) if the whitelist has one
+    pub fn get_name(&self, b_index: usize, position: usize) -> Option<&str> {
+        self.barcodes.get(position).and_then(|bc| bc.get_name(b_index))
+    }
+
+    /// Labels a barcode by its round and its name, or its sequence
+    /// if the whitelist has no name for it, e.g. `bc2=C07`
+    pub fn label(&self, b_index: usize, position: usize) -> String {
+        let entry = match self.get_name(b_index, position) {
+            Some(name) => name.to_string(),
+            None => self
+                .get_barcode(b_index, position)
+                .map_or_else(|| "unknown".to_string(), |bc| String::from_utf8_lossy(bc).into_owned()),
+        };
+        format!("bc{}={}", position + 1, entry)
+    }
+
 }
 
 #[cfg(test)]
 mod testing {
 
     use super::*;
-    use env_logger;
+    use clap::ValueEnum;
 
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -140,11 +804,231 @@ mod testing {
         assert!(config.is_ok());
     }
 
+    #[test]
+    fn match_insertion_at_max_shift() {
+        let config = Config::from_file_with_tolerance(TEST_PATH, &Tolerance::new(false, true), false).unwrap();
+        // AGAAACCA (bc1) with an insertion, shifted by 2 and followed by its spacer
+        let seq = b"NNAGAAGACCAATGTTTTTTTT";
+        let m = config.match_round(seq, None, 0, 0, (0, 2)).unwrap();
+        assert_eq!((m.start, m.end, m.id, m.correction), (2, 14, 0, Correction::Indel));
+        // the window isn't widened for the barcodes themselves
+        let seq = b"NNNAGAAACCAATGTTTTTTT";
+        assert!(config.match_round(seq, None, 0, 0, (0, 2)).is_none_or(|m| m.correction != Correction::Exact));
+        assert_eq!(config.match_round(seq, None, 0, 0, (0, 3)).map(|m| m.correction), Some(Correction::Exact));
+    }
+
+    #[test]
+    fn combination_ids() {
+        let config = Config::from_file(TEST_PATH, true, false).unwrap();
+        let sizes = config.barcodes.iter().map(|bc| bc.num_entries() as u128).collect::<Vec<_>>();
+        assert_eq!(config.combination_id(&[0, 0, 0, 0]), 0);
+        assert_eq!(config.combination_id(&[0, 0, 0, 1]), 1);
+        assert_eq!(config.combination_id(&[0, 0, 1, 0]), sizes[3]);
+        // ids of large whitelists no longer collide
+        assert_ne!(config.combination_id(&[0, 0, 0, 256]), config.combination_id(&[0, 0, 1, 0]));
+        let last = sizes.iter().map(|&s| s as usize - 1).collect::<Vec<_>>();
+        assert_eq!(config.combination_id(&last), sizes.iter().product::<u128>() - 1);
+    }
+
+    fn v3_onlists() -> IndexMap<String, String> {
+        (1..=4)
+            .map(|i| (format!("bc{}", i), format!("data/barcodes_v3/fb_v3_bc{}.tsv", i)))
+            .collect()
+    }
+
+    #[test]
+    fn load_read_structure() {
+        let structure = "8B[bc1] 3S[ATG] 6B[bc2] 3S[GAG] 6B[bc3] 5S[TCGAG] 8B[bc4] 12U"
+            .parse::<ReadStructure>()
+            .unwrap();
+        let config = Config::from_read_structure(&structure, &v3_onlists(), &Tolerance::new(false, false), false).unwrap();
+        let yaml_config = Config::from_file(TEST_PATH, false, false).unwrap();
+        assert_eq!(config.barcode_count(), 4);
+        assert_eq!(config.umi_len(), 12);
+        assert_eq!(config.build_barcode(&[1, 2, 3, 4]), yaml_config.build_barcode(&[1, 2, 3, 4]));
+        let seq = b"NATACTGAATATGGTAATCGAGATCTGATCGAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC";
+        assert_eq!(
+            config.match_subsequence(seq, 0, 0, Some(5)),
+            yaml_config.match_subsequence(seq, 0, 0, Some(5))
+        );
+    }
+
+    #[test]
+    fn load_read_structure_invalid() {
+        let tolerance = Tolerance::new(false, false);
+        // missing onlist
+        let structure = "8B[bc1] 3S[ATG] 6B[bc5] 12U".parse::<ReadStructure>().unwrap();
+        assert!(Config::from_read_structure(&structure, &v3_onlists(), &tolerance, false).is_err());
+        // the segment length doesn't match the whitelist
+        let structure = "8B[bc1] 3S[ATG] 8B[bc2] 12U".parse::<ReadStructure>().unwrap();
+        assert!(Config::from_read_structure(&structure, &v3_onlists(), &tolerance, false).is_err());
+    }
+
+    #[test]
+    fn load_seqspec() {
+        let construct = Construct::from_file("configs/20260521/vbcf_seqspec_20260521.yaml").unwrap();
+        let onlists = [
+            ("barcode1", "configs/20260521/bc1_custom_primer.txt"),
+            ("barcode2", "configs/20260521/bc2.txt"),
+            ("barcode3", "configs/20260521/bc3.txt"),
+            ("phase_block", "data/vbcf_phase_block.txt"),
+        ]
+        .into_iter()
+        .map(|(name, path)| (name.to_string(), path.to_string()))
+        .collect();
+        let config = Config::from_seqspec(&construct, &onlists, &Tolerance::new(false, false), false).unwrap();
+        let yaml_config = Config::from_file("data/config_vbcf.yaml", false, false).unwrap();
+        assert_eq!(config.barcode_count(), 3);
+        assert_eq!(config.umi_len(), 14);
+        assert!(config.has_stagger());
+        assert_eq!(config.build_barcode(&[1, 2, 3]), yaml_config.build_barcode(&[1, 2, 3]));
+        let seq = b"GTCAAGTTCCGTAGTAACCCTATGGAGACAGACGCTTGTCCTATTCGAGACGTACGTACGTACGG";
+        let matched = config.match_staggered(seq, None).map(|(m, len)| (m.end, m.id, len));
+        assert_eq!(matched, yaml_config.match_staggered(seq, None).map(|(m, len)| (m.end, m.id, len)));
+        assert!(matched.is_some());
+    }
+
+    #[test]
+    fn load_yaml_relative_paths() {
+        // the whitelists are next to the config, not in the working directory
+        let config = Config::from_file("configs/20260521/20250716_config.yaml", false, false).unwrap();
+        let expected = std::env::current_dir().unwrap().join("configs/20260521/bc2.txt");
+        assert_eq!(config.paths()[1], expected.to_string_lossy());
+        let config = Config::from_file("data/config_vbcf.yaml", false, false).unwrap();
+        assert!(config.stagger_onlist().unwrap().ends_with("data/vbcf_phase_block.txt"));
+    }
+
+    #[test]
+    fn load_presets() {
+        let tolerance = Tolerance::new(false, false);
+        let config = Config::from_preset(Chemistry::PipseqV3, None, &tolerance, false).unwrap();
+        let yaml_config = Config::from_file(TEST_PATH, false, false).unwrap();
+        assert_eq!(config.build_barcode(&[1, 2, 3, 4]), yaml_config.build_barcode(&[1, 2, 3, 4]));
+        assert_eq!(config.paths()[0], "builtin:pipseq-v3/bc1");
+        let parameters = config.parameters();
+        assert_eq!((parameters.umi_len, parameters.offset, parameters.umi_offset), (Some(12), Some(5), Some(0)));
+        let config = Config::from_preset(Chemistry::Vbcf, None, &tolerance, false).unwrap();
+        assert!(config.has_stagger());
+        assert_eq!(config.umi_len(), 14);
+        for &chemistry in Chemistry::value_variants() {
+            assert!(Config::from_preset(chemistry, None, &tolerance, false).is_ok());
+        }
+    }
+
+    #[test]
+    fn load_preset_overrides() {
+        // bc1 and the UMI length are replaced, the rest of the preset is kept
+        let path = std::env::temp_dir().join("pipspeak_preset_overrides.yaml");
+        let bc1 = std::env::current_dir().unwrap().join("configs/20260521/bc1_custom_primer.txt");
+        let overrides = format!(
+            "barcodes:\n  bc1: {}\n  bc2: {{mismatches: 0}}\nparameters:\n  umi_len: 12\n",
+            bc1.display()
+        );
+        std::fs::write(&path, overrides).unwrap();
+        let config = Config::from_preset(
+            Chemistry::Vbcf,
+            path.to_str(),
+            &Tolerance::new(false, false),
+            false,
+        )
+        .unwrap();
+        assert_eq!(config.paths()[0], bc1.to_string_lossy());
+        assert_eq!(config.paths()[1], "builtin:vbcf/bc2");
+        assert_eq!(config.mismatches(), vec![1, 0, 1]);
+        assert_eq!(config.umi_len(), 12);
+        assert!(config.has_stagger());
+    }
+
     #[test]
     fn load_yaml_umi_len() {
         let config = Config::from_file("data/config_v3_umi_len.yaml", false, false);
         assert!(config.is_ok());
-        assert!(config.unwrap().umi_len == 8)
+        assert!(config.unwrap().umi_len() == 8)
+    }
+
+    #[test]
+    fn load_yaml_mismatches() {
+        let config = Config::from_file("data/config_v3_mismatches.yaml", false, false).unwrap();
+        assert_eq!(config.mismatches(), vec![0, 1, 1, 1]);
+        assert_eq!(config.spacer_mismatches(), vec![None, None, Some(1), None]);
+        let config = Config::from_file("data/config_v3_mismatches.yaml", true, false).unwrap();
+        assert_eq!(config.mismatches(), vec![0, 0, 0, 0]);
+        assert_eq!(config.spacer_mismatches(), vec![None, None, Some(0), None]);
+    }
+
+    #[test]
+    fn load_yaml_two_mismatches() {
+        let round = |path: &str, mismatches| BarcodeRound {
+            path: path.to_string(),
+            mismatches,
+            ..Default::default()
+        };
+        let yaml = ConfigYaml {
+            barcodes: vec![
+                round("configs/20260521/bc1_custom_primer.txt", Some(2)),
+                round("configs/20260521/bc2.txt", None),
+            ],
+            spacers: vec![
+                SpacerRound { seq: "AACC".to_string(), mismatches: None },
+                SpacerRound { seq: "ACAG".to_string(), mismatches: None },
+            ],
+            umi_anchor: None,
+            stagger: None,
+            parameters: None,
+        };
+        let config = Config::from_yaml(yaml, &Tolerance::new(false, false), false).unwrap();
+        assert_eq!(config.mismatches(), vec![2, 1]);
+        // TCCGTAGT with two substitutions
+        assert_eq!(config.match_subsequence(b"ACCGTAGAAACC", 0, 0, None), Some((12, 0)));
+    }
+
+    #[test]
+    fn load_yaml_colliding_mismatches() {
+        let yaml = ConfigYaml {
+            barcodes: vec![BarcodeRound {
+                path: "data/barcodes_v3/fb_v3_bc2.tsv".to_string(),
+                mismatches: Some(2),
+                ..Default::default()
+            }],
+            spacers: vec![],
+            umi_anchor: None,
+            stagger: None,
+            parameters: None,
+        };
+        assert!(Config::from_yaml(yaml, &Tolerance::new(false, false), false).is_err());
+    }
+
+    #[test]
+    fn load_yaml_table() {
+        let path = std::env::temp_dir().join("pipspeak_bc1_table.csv");
+        std::fs::write(&path, "# plate 1\nwell,sequence\nA01,AGAAACCA\nA02,GATTTCCC\n\nA03,AAGTCCAA\n").unwrap();
+        let yaml = serde_yaml::from_str::<ConfigYamlRead>(&format!(
+            "barcodes:\n  bc1: {{path: {}, sequence_column: sequence, name_column: well}}\nspacers: {{}}\n",
+            path.display()
+        ))
+        .unwrap();
+        let yaml = ConfigYaml::from_read_yaml(yaml, Path::new("")).unwrap();
+        let config = Config::from_yaml(yaml, &Tolerance::new(true, false), false).unwrap();
+        assert_eq!(config.get_barcode(2, 0), Some(&b"AAGTCCAA"[..]));
+        assert_eq!(config.get_name(2, 0), Some("A03"));
+    }
+
+    #[test]
+    fn barcode_labels() {
+        let path = std::env::temp_dir().join("pipspeak_bc1_named.tsv");
+        std::fs::write(&path, "AGAAACCA\tA01\nGATTTCCC\n").unwrap();
+        let round = BarcodeRound { path: path.to_string_lossy().into_owned(), ..Default::default() };
+        let yaml = ConfigYaml {
+            barcodes: vec![round.clone(), round],
+            spacers: vec![],
+            umi_anchor: None,
+            stagger: None,
+            parameters: None,
+        };
+        let config = Config::from_yaml(yaml, &Tolerance::new(true, false), false).unwrap();
+        assert_eq!(config.label(0, 1), "bc2=A01");
+        assert_eq!(config.label(1, 0), "bc1=GATTTCCC");
+        assert_eq!(config.label(2, 0), "bc1=unknown");
     }
 
     #[test]
@@ -233,7 +1117,7 @@ mod testing {
     #[test]
     fn construct_building_a() {
         let config = Config::from_file(TEST_PATH, false, false).unwrap();
-        let bc = config.build_barcode(&vec![0, 0, 0, 0]); 
+        let bc = config.build_barcode(&[0, 0, 0, 0]); 
         let exp = [
             "AGAAACCA".as_bytes(),
             "TCTGTG".as_bytes(),
@@ -247,7 +1131,7 @@ mod testing {
     #[test]
     fn construct_building_b() {
         let config = Config::from_file(TEST_PATH, false, false).unwrap();
-        let bc = config.build_barcode(&vec![0, 95, 0, 95]); 
+        let bc = config.build_barcode(&[0, 95, 0, 95]); 
         let exp = [
             "AGAAACCA".as_bytes(),
             "GTAATC".as_bytes(),
@@ -261,7 +1145,7 @@ mod testing {
     #[test]
     fn construct_building_a_exact() {
         let config = Config::from_file(TEST_PATH, true, false).unwrap();
-        let bc = config.build_barcode(&vec![0, 0, 0, 0]); 
+        let bc = config.build_barcode(&[0, 0, 0, 0]); 
         let exp = [
             "AGAAACCA".as_bytes(),
             "TCTGTG".as_bytes(),
@@ -275,7 +1159,7 @@ mod testing {
     #[test]
     fn construct_building_b_exact() {
         let config = Config::from_file(TEST_PATH, true, false).unwrap();
-        let bc = config.build_barcode(&vec![0, 95, 0, 95]); 
+        let bc = config.build_barcode(&[0, 95, 0, 95]); 
         let exp = [
             "AGAAACCA".as_bytes(),
             "GTAATC".as_bytes(),
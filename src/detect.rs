@@ -0,0 +1,242 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use fxread::{initialize_reader, Record};
+use hashbrown::HashMap;
+use serde::Serialize;
+use std::{fmt::Write, thread};
+
+use crate::chemistry::Chemistry;
+use crate::config::{Config, ConfigYaml};
+use crate::orientation::Orientation;
+use crate::parser::barcode_span;
+
+/// Maximum shift of bc1 searched in detection
+const DETECT_MAX_OFFSET: usize = 12;
+
+/// Fraction of the matching reads whose bc1 lies within the reported offset
+const OFFSET_COVERAGE: f64 = 0.99;
+
+/// Minimum fraction of sampled reads the winner has to match
+const MIN_PASS_RATE: f64 = 0.2;
+
+/// Minimum confidence to continue with the winner
+const MIN_CONFIDENCE: f64 = 0.5;
+
+/// A construct tried in detection
+pub struct Candidate {
+    pub name: String,
+    pub chemistry: Option<Chemistry>,
+    pub config: Config,
+}
+
+/// How well the sampled reads match a candidate
+#[derive(Debug, Serialize)]
+pub struct CandidateResult {
+    /// Index of the candidate
+    #[serde(skip)]
+    pub index: usize,
+    pub name: String,
+    pub passing_reads: usize,
+    pub pass_rate: f64,
+    /// Smallest offset covering bc1 of nearly all matching reads,
+    /// none if bc1 is placed by a stagger segment
+    pub offset: Option<usize>,
+    /// Most common start of the UMI in R1
+    pub umi_start: Option<usize>,
+}
+
+/// The detection results of all candidates, best first
+#[derive(Debug, Serialize)]
+pub struct Detection {
+    pub sample_size: usize,
+    pub candidates: Vec<CandidateResult>,
+    /// One minus the ratio of the runner-up's to the winner's pass rate
+    pub confidence: f64,
+}
+
+/// Returns the built-in presets followed by the given configs,
+/// each loaded with the parameters it sets
+pub fn candidates(configs: &[String], load: impl Fn(ConfigYaml) -> Result<Config>) -> Result<Vec<Candidate>> {
+    let mut candidates = Chemistry::value_variants()
+        .iter()
+        .map(|&chemistry| {
+            Ok(Candidate {
+                name: chemistry.name().to_string(),
+                chemistry: Some(chemistry),
+                config: load(ConfigYaml::from_preset(chemistry, None)?)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for path in configs {
+        candidates.push(Candidate {
+            name: path.clone(),
+            chemistry: None,
+            config: load(ConfigYaml::from_file(path)?)?,
+        });
+    }
+    Ok(candidates)
+}
+
+/// Reads the first read pairs with the barcode read first
+pub fn sample(r1: &str, r2: &str, orientation: Option<Orientation>, size: usize) -> Result<Vec<(Record, Record)>> {
+    let orientation = orientation.unwrap_or_default();
+    initialize_reader(r1)?
+        .zip(initialize_reader(r2)?)
+        .take(size)
+        .map(|(rec1, rec2)| orientation.apply(rec1, rec2))
+        .collect()
+}
+
+/// Matches the sample against a candidate
+fn evaluate(sample: &[(Record, Record)], index: usize, candidate: &Candidate) -> CandidateResult {
+    let config = &candidate.config;
+    let mut shifts = [0; DETECT_MAX_OFFSET + 1];
+    let mut umi_starts = HashMap::new();
+    let mut passing_reads = 0;
+    for (rec1, _) in sample {
+        if let Some((shift, pos)) = barcode_span(rec1, DETECT_MAX_OFFSET, config) {
+            passing_reads += 1;
+            shifts[shift.min(DETECT_MAX_OFFSET)] += 1;
+            // the UMI follows its anchor, or the umi_offset without one
+            let umi_offset = config.umi_anchor_len().or(config.parameters().umi_offset).unwrap_or(0);
+            *umi_starts.entry(pos + umi_offset).or_insert(0) += 1;
+        }
+    }
+
+    let offset = if config.has_stagger() || passing_reads == 0 {
+        None
+    } else {
+        let mut covered = 0;
+        shifts.iter().position(|&count| {
+            covered += count;
+            covered as f64 >= OFFSET_COVERAGE * passing_reads as f64
+        })
+    };
+    // ties keep the earlier position
+    let umi_start = umi_starts
+        .into_iter()
+        .max_by_key(|&(pos, count)| (count, std::cmp::Reverse(pos)))
+        .map(|(pos, _)| pos);
+    CandidateResult {
+        index,
+        name: candidate.name.clone(),
+        passing_reads,
+        pass_rate: if sample.is_empty() { 0.0 } else { passing_reads as f64 / sample.len() as f64 },
+        offset,
+        umi_start,
+    }
+}
+
+/// Matches the sample against every candidate, one thread per candidate
+pub fn detect(sample: &[(Record, Record)], candidates: &[Candidate]) -> Detection {
+    let mut results = thread::scope(|scope| {
+        candidates
+            .iter()
+            .enumerate()
+            .map(|(index, candidate)| scope.spawn(move || evaluate(sample, index, candidate)))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().expect("Detection thread panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    // stable sort keeps the presets before configs on ties
+    results.sort_by_key(|result| std::cmp::Reverse(result.passing_reads));
+    let rate = |rank: usize| results.get(rank).map_or(0.0, |result| result.pass_rate);
+    let confidence = if rate(0) > 0.0 { 1.0 - rate(1) / rate(0) } else { 0.0 };
+    Detection {
+        sample_size: sample.len(),
+        candidates: results,
+        confidence,
+    }
+}
+
+impl Detection {
+    /// Returns the index of the winning candidate, or an error if no
+    /// candidate matches enough reads or the runner-up is too close
+    pub fn winner(&self) -> Result<usize> {
+        let Some(best) = self.candidates.first() else {
+            bail!("No candidates to detect the chemistry from");
+        };
+        if best.pass_rate < MIN_PASS_RATE {
+            bail!(
+                "No candidate matches at least {:.0}% of the first {} reads, the best is {} with {:.2}%",
+                MIN_PASS_RATE * 100.0,
+                self.sample_size,
+                best.name,
+                best.pass_rate * 100.0
+            );
+        }
+        if self.confidence < MIN_CONFIDENCE {
+            bail!(
+                "{} and {} match similarly many reads (confidence {:.2}), choose one with --chemistry or --config",
+                best.name,
+                self.candidates[1].name,
+                self.confidence
+            );
+        }
+        Ok(best.index)
+    }
+
+    /// Returns the results as a table, best candidate first
+    pub fn table(&self) -> String {
+        let width = self.candidates.iter().map(|c| c.name.len()).max().unwrap_or(0).max(9);
+        let mut table = format!(
+            "{:<width$}  {:>9}  {:>8}  {:>7}  {:>9}\n",
+            "candidate", "pass_rate", "passing", "offset", "umi_start"
+        );
+        let position = |pos: Option<usize>, missing: &str| pos.map_or(missing.to_string(), |p| p.to_string());
+        for candidate in &self.candidates {
+            let _ = writeln!(
+                table,
+                "{:<width$}  {:>8.2}%  {:>8}  {:>7}  {:>9}",
+                candidate.name,
+                candidate.pass_rate * 100.0,
+                candidate.passing_reads,
+                position(candidate.offset, if candidate.passing_reads > 0 { "stagger" } else { "-" }),
+                position(candidate.umi_start, "-"),
+            );
+        }
+        let _ = writeln!(table, "confidence: {:.2} over {} read pairs", self.confidence, self.sample_size);
+        table
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::barcodes::Tolerance;
+
+    fn load(yaml: ConfigYaml) -> Result<Config> {
+        Config::from_yaml(yaml, &Tolerance::new(false, false), false)
+    }
+
+    const R1: &str = "data/example_v3/example_R1.fq.gz";
+    const R2: &str = "data/example_v3/example_R2.fq.gz";
+
+    #[test]
+    fn detect_v3() {
+        let candidates = candidates(&[], load).unwrap();
+        let sample = sample(R1, R2, None, 1000).unwrap();
+        let detection = detect(&sample, &candidates);
+        assert_eq!(detection.sample_size, 250);
+        let winner = detection.winner().unwrap();
+        assert_eq!(candidates[winner].chemistry, Some(Chemistry::PipseqV3));
+        let best = &detection.candidates[0];
+        assert!(best.pass_rate > 0.7);
+        assert!(best.offset.is_some_and(|offset| offset <= 5));
+        assert!(detection.confidence > 0.9);
+        assert!(detection.table().lines().nth(1).unwrap().starts_with("pipseq-v3"));
+    }
+
+    #[test]
+    fn detect_swapped() {
+        let candidates = candidates(&[], load).unwrap();
+        // the barcodes are on R2
+        let swapped = sample(R2, R1, None, 1000).unwrap();
+        assert!(detect(&swapped, &candidates).winner().is_err());
+        let swapped = sample(R2, R1, Some(Orientation::Swapped), 1000).unwrap();
+        let winner = detect(&swapped, &candidates).winner().unwrap();
+        assert_eq!(candidates[winner].name, "pipseq-v3");
+    }
+}
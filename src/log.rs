@@ -1,14 +1,23 @@
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     fs::File,
     io::{BufWriter, Write},
 };
 
 use anyhow::Result;
 use hashbrown::HashSet;
+use indexmap::IndexMap;
 use serde::Serialize;
 use serde::ser::{Serializer, SerializeMap};
 
+use crate::barcodes::{BarcodeMatch, Correction};
+use crate::chemistry::ChemistryInfo;
 use crate::config::Config;
+use crate::detect::Detection;
+use crate::orientation::Orientation;
+use crate::packed;
+use crate::settings::Settings;
 
 use log::trace;
 
@@ -20,48 +29,143 @@ pub struct Statistics {
     pub whitelist_size: usize,
     pub num_filtered: Vec<usize>,
     pub num_filtered_umi: usize,
+    /// Reads filtered per round for equally good placements of different barcodes
+    pub num_ambiguous: Vec<usize>,
+    /// Passing reads with at least one ambiguous barcode resolved by base qualities
+    pub num_resolved: usize,
+    /// Passing reads with at least one barcode matched through N wildcards
+    pub num_wildcard: usize,
+    /// Barcode read orientation used for the run
+    pub orientation: Orientation,
+    /// Passing reads matched on the reverse complement with mixed orientation
+    pub num_reverse_complement: usize,
+    /// Passing reads with a corrected barcode per round
+    pub num_corrected: Vec<usize>,
+    /// Passing reads with a corrected spacer per round
+    pub num_spacer_corrected: Vec<usize>,
+    /// Passing reads per observed shift of each round
+    pub shifts: Vec<BTreeMap<usize, usize>>,
+    /// Passing reads per observed stagger length
+    pub stagger_lengths: BTreeMap<usize, usize>,
+    /// Passing reads of the most frequent barcodes of each round by their label
+    pub top_barcodes: Vec<IndexMap<String, usize>>,
     pub whitelist: HashSet<Vec<u8>>,
     pub counter_maps: BarcodePartCounterMaps,
     pub barcode_umi_counter: BarcodeUmiCounter,
     pub umi_base_composition: UMIBaseComposition,
 }
 impl Statistics {
-    pub fn new(barcode_count: usize) -> Self {
+    pub fn new(barcode_count: usize, umi_len: usize) -> Self {
         Self {
             counter_maps: BarcodePartCounterMaps::new(barcode_count),
             barcode_umi_counter: BarcodeUmiCounter::new(),
-            umi_base_composition: UMIBaseComposition::new(16),
+            umi_base_composition: UMIBaseComposition::new(umi_len),
             num_filtered: vec![0; barcode_count],
+            num_ambiguous: vec![0; barcode_count],
+            num_corrected: vec![0; barcode_count],
+            num_spacer_corrected: vec![0; barcode_count],
+            shifts: vec![BTreeMap::new(); barcode_count],
             ..Self::default()
         }
     }
+    /// Counts the corrections of the barcode matches of a passing read
+    pub fn add_matches(&mut self, matches: &[BarcodeMatch]) {
+        for (i, m) in matches.iter().enumerate() {
+            if m.barcode_corrected() {
+                self.num_corrected[i] += 1;
+            }
+            if m.spacer_errors > 0 {
+                self.num_spacer_corrected[i] += 1;
+            }
+            *self.shifts[i].entry(m.start).or_default() += 1;
+        }
+        if matches.iter().any(|m| m.correction == Correction::Resolved) {
+            self.num_resolved += 1;
+        }
+        if matches.iter().any(|m| m.correction == Correction::Wildcard) {
+            self.num_wildcard += 1;
+        }
+    }
+    /// Adds the counts of another run over different reads,
+    /// e.g. of a worker thread
+    pub fn merge(&mut self, other: Statistics) {
+        fn add_each(a: &mut [usize], b: &[usize]) {
+            a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+        }
+        self.total_reads += other.total_reads;
+        self.passing_reads += other.passing_reads;
+        add_each(&mut self.num_filtered, &other.num_filtered);
+        add_each(&mut self.num_ambiguous, &other.num_ambiguous);
+        self.num_filtered_umi += other.num_filtered_umi;
+        self.num_resolved += other.num_resolved;
+        self.num_wildcard += other.num_wildcard;
+        self.num_reverse_complement += other.num_reverse_complement;
+        add_each(&mut self.num_corrected, &other.num_corrected);
+        add_each(&mut self.num_spacer_corrected, &other.num_spacer_corrected);
+        for (shifts, other_shifts) in self.shifts.iter_mut().zip(other.shifts) {
+            for (shift, count) in other_shifts {
+                *shifts.entry(shift).or_default() += count;
+            }
+        }
+        for (len, count) in other.stagger_lengths {
+            *self.stagger_lengths.entry(len).or_default() += count;
+        }
+        self.whitelist.extend(other.whitelist);
+        self.counter_maps.merge(other.counter_maps);
+        self.barcode_umi_counter.merge(other.barcode_umi_counter);
+        self.umi_base_composition.merge(&other.umi_base_composition);
+    }
     pub fn calculate_metrics(&mut self) {
         self.fraction_passing = self.passing_reads as f64 / self.total_reads as f64;
         self.whitelist_size = self.whitelist.len();
     }
+    /// Labels the `n` most frequent barcodes of each round for the log
+    pub fn label_top_barcodes(&mut self, config: &Config, n: usize) {
+        self.top_barcodes = self
+            .counter_maps
+            .maps
+            .iter()
+            .enumerate()
+            .map(|(position, map)| {
+                let map = map.lock().unwrap();
+                let mut counts = map.iter().collect::<Vec<_>>();
+                counts.sort_unstable_by(|(a_idx, a), (b_idx, b)| b.cmp(a).then(a_idx.cmp(b_idx)));
+                let mut top = IndexMap::new();
+                for (k, v) in counts.into_iter().take(n) {
+                    *top.entry(config.label(*k, position)).or_default() += *v;
+                }
+                top
+            })
+            .collect();
+    }
     pub fn whitelist_to_file(&self, file: &str) -> Result<()> {
         let mut writer = File::create(file).map(BufWriter::new)?;
         for seq in &self.whitelist {
-            writer.write(seq)?;
-            writer.write(b"\n")?;
+            writer.write_all(seq)?;
+            writer.write_all(b"\n")?;
         }
         Ok(())
     }
-    pub fn barcode_umi_stats_to_file(&self, file: &str) -> std::io::Result<()> {
-        self.barcode_umi_counter.write_barcode_stats(file)
+    pub fn barcode_umi_stats_to_file(&self, file: &str, config: &Config) -> std::io::Result<()> {
+        self.barcode_umi_counter.write_barcode_stats(file, config)
     }
+    /// Writes the passing reads per barcode of each round, labelled
+    /// by the name of the barcode if its whitelist has one
     pub fn counter_maps_to_file(&self, file: &str, config: &Config) -> Result<()> {
         let mut writer = File::create(file).map(BufWriter::new)?;
-        let _ = writer.write(b"position\tbarcode\tcount\n");
+        let _ = writer.write(b"position\tbarcode\tlabel\tcount\n");
         for (position, map) in self.counter_maps.maps.iter().enumerate() {
             let map = map.lock().unwrap();
-            for (k, v) in map.iter() {
-                trace!("bc_index: {} set: {} barcode: {:?}", k, v, config.get_barcode(*k, position));
+            let mut counts = map.iter().collect::<Vec<_>>();
+            counts.sort_unstable_by_key(|(k, _)| **k);
+            for (k, v) in counts {
+                let label = config.label(*k, position);
+                trace!("bc_index: {} count: {} {}", k, v, label);
 
                 let bc = config.get_barcode(*k, position)
                     .and_then(|bc| String::from_utf8(bc.to_vec()).ok())
                     .unwrap_or_else(|| "unknown".to_string());
-                writer.write_all(format!("{}\t{}\t{}\n", position, bc, v).as_bytes())?;
+                writer.write_all(format!("{}\t{}\t{}\t{}\n", position, bc, label, v).as_bytes())?;
             }
         }
         Ok(())
@@ -73,7 +177,10 @@ impl Serialize for Statistics {
     where
         S: Serializer,
     {
-        let num_fields = 5 + self.num_filtered.len();
+        let num_fields = 9
+            + 5 * self.num_filtered.len()
+            + self.top_barcodes.len()
+            + usize::from(!self.stagger_lengths.is_empty());
         let mut map = serializer.serialize_map(Some(num_fields))?;
         
         map.serialize_entry("total_reads", &self.total_reads)?;
@@ -86,7 +193,40 @@ impl Serialize for Statistics {
             map.serialize_entry(&field_name, value)?;
         }
         
+        for (i, value) in self.num_ambiguous.iter().enumerate() {
+            let field_name = format!("num_ambiguous_{}", i + 1);
+            map.serialize_entry(&field_name, value)?;
+        }
+
         map.serialize_entry("num_filtered_umi", &self.num_filtered_umi)?;
+        map.serialize_entry("num_resolved", &self.num_resolved)?;
+        map.serialize_entry("num_wildcard", &self.num_wildcard)?;
+        map.serialize_entry("orientation", &self.orientation)?;
+        map.serialize_entry("num_reverse_complement", &self.num_reverse_complement)?;
+
+        for (i, value) in self.num_corrected.iter().enumerate() {
+            let field_name = format!("num_corrected_{}", i + 1);
+            map.serialize_entry(&field_name, value)?;
+        }
+
+        for (i, value) in self.num_spacer_corrected.iter().enumerate() {
+            let field_name = format!("num_spacer_corrected_{}", i + 1);
+            map.serialize_entry(&field_name, value)?;
+        }
+
+        for (i, value) in self.shifts.iter().enumerate() {
+            let field_name = format!("shifts_{}", i + 1);
+            map.serialize_entry(&field_name, value)?;
+        }
+
+        if !self.stagger_lengths.is_empty() {
+            map.serialize_entry("stagger_lengths", &self.stagger_lengths)?;
+        }
+
+        for (i, value) in self.top_barcodes.iter().enumerate() {
+            let field_name = format!("top_barcodes_{}", i + 1);
+            map.serialize_entry(&field_name, value)?;
+        }
         
         map.end()
     }
@@ -100,6 +240,11 @@ pub struct Timing {
 
 #[derive(Debug, Serialize)]
 pub struct FileIO {
+    /// Absolute path of the config or seqspec
+    pub config_path: Option<String>,
+    /// Absolute whitelist path of each round
+    pub barcode_paths: Vec<String>,
+    pub stagger_onlist: Option<String>,
     pub readpath_r1: String,
     pub readpath_r2: String,
     pub writepath_r1: String,
@@ -109,10 +254,15 @@ pub struct FileIO {
 
 #[derive(Debug, Serialize)]
 pub struct Parameters {
-    pub offset: usize,
-    pub umi_len: usize,
-    pub exact_matching: bool,
-    pub write_linkers: bool,
+    /// The effective processing parameters and their sources
+    #[serde(flatten)]
+    pub settings: Settings,
+    pub barcode_mismatches: Vec<usize>,
+    pub spacer_mismatches: Vec<Option<usize>>,
+    /// The effective read structure, if the construct was given as one
+    pub read_structure: Option<String>,
+    /// The built-in chemistry preset, if one was used
+    pub chemistry: Option<ChemistryInfo>,
     pub pipspeak_version: String,
 }
 
@@ -120,6 +270,9 @@ pub struct Parameters {
 /// A struct to hold the information about the run
 pub struct Log {
     pub parameters: Parameters,
+    /// The chemistry detection results, if the chemistry was detected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detection: Option<Detection>,
     pub file_io: FileIO,
     pub statistics: Statistics,
     pub timing: Timing,
@@ -169,17 +322,53 @@ impl BarcodePartCounterMaps {
         let mut map = self.maps[position].lock().unwrap();
         *map.entry(index).or_insert(0) += 1;
     }
+
+    pub fn merge(&self, other: Self) {
+        for (map, other_map) in self.maps.iter().zip(other.maps) {
+            let mut map = map.lock().unwrap();
+            for (index, count) in other_map.into_inner().unwrap() {
+                *map.entry(index).or_insert(0) += count;
+            }
+        }
+    }
 }
 
 
 
 
+/// Quotes a CSV field containing commas, quotes or line breaks
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// A UMI packed with 2 bits per nucleotide below 32 nt,
+/// longer UMIs (or other bases) are kept as they are
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub enum UmiKey {
+    Packed(u64),
+    Sequence(Box<[u8]>),
+}
+
+impl UmiKey {
+    pub fn new(umi: &[u8]) -> Self {
+        // a leading marker bit keeps UMIs of different lengths apart
+        match packed::encode(umi) {
+            Some(code) if umi.len() < packed::MAX_PACKED_LEN => Self::Packed(code | 1 << (2 * umi.len())),
+            _ => Self::Sequence(umi.into()),
+        }
+    }
+}
+
 /// A struct to hold the UMI counts
-/// encodes the UMI as a u32
+/// encodes the UMI as a UmiKey
 /// and the count as a u32
 #[derive(Debug, Default, Serialize)]
 pub struct UmiCounter {
-    map: Mutex<HashMap<u32, u32>>,
+    map: Mutex<HashMap<UmiKey, u32>>,
 }
 
 impl Clone for UmiCounter {
@@ -192,34 +381,16 @@ impl Clone for UmiCounter {
 }
 
 impl UmiCounter {
-    pub fn new() -> Self {
-        Self {
-            map: Mutex::new(HashMap::new()),
-        }
-    }
-
-    pub fn umi2u32(umi: &Vec<u8>) -> u32 {
-        if umi.len() > 16 {
-            panic!("UMI length is greater than 16")
-        }
-        let mut res = 0;
-        for &b in umi {
-            res <<= 2;
-            match b {
-                b'A' => res |= 0,
-                b'C' => res |= 1,
-                b'G' => res |= 2,
-                b'T' => res |= 3,
-                _ => panic!("Invalid UMI base"),
-            }
-        }
-        res
+    pub fn add(&self, umi: &[u8]) {
+        let mut map = self.map.lock().unwrap();
+        *map.entry(UmiKey::new(umi)).or_insert(0) += 1;
     }
 
-    pub fn add(&self, umi: &Vec<u8>) {
-        let umi_e = Self::umi2u32(umi);
+    pub fn merge(&self, other: Self) {
         let mut map = self.map.lock().unwrap();
-        *map.entry(umi_e).or_insert(0) += 1;
+        for (umi, count) in other.map.into_inner().unwrap() {
+            *map.entry(umi).or_insert(0) += count;
+        }
     }
 }
 
@@ -228,8 +399,9 @@ impl UmiCounter {
 
 
 /// Holds the barcode and UMI counts
-/// encodes the barcode as a u32
-/// and the UMI as a u32
+/// keyed by the barcode index of each round
+/// and the UMI as a `UmiKey`, packed with a marker bit
+/// for its length or kept as its sequence
 #[derive(Debug, Default, Serialize)]
 pub struct BarcodeUmiCounter {
     map: Mutex<HashMap<Vec<usize>, UmiCounter>>,
@@ -252,37 +424,45 @@ impl BarcodeUmiCounter {
         }
     }
 
-    pub fn barcodes2u32(indices: &Vec<usize>) -> u32 {
-        // Ensure the vector has at least one element
-        assert!(!indices.is_empty(), "The input vector must have at least one element");
-    
-        // Pad the vector with zeros if its length is less than 4
-        let mut padded_indices = indices.clone();
-        while padded_indices.len() < 4 {
-            padded_indices.push(0);
-        }
-    
-        // Convert the first four elements to u8
-        let b1 = padded_indices[0] as u8;
-        let b2 = padded_indices[1] as u8;
-        let b3 = padded_indices[2] as u8;
-        let b4 = padded_indices[3] as u8;
-    
-        // Combine the elements into a single u32 value
-        ((b1 as u32) << 24) | ((b2 as u32) << 16) | ((b3 as u32) << 8) | (b4 as u32)
+    pub fn add(&self, barcode_indices: &[usize], umi: &[u8]) {
+        let mut map = self.map.lock().unwrap();
+        map.entry(barcode_indices.to_vec()).or_default().add(umi);
     }
 
-    pub fn add(&self, barcode_indices: &[usize], umi: &Vec<u8>) {
+    pub fn merge(&self, other: Self) {
         let mut map = self.map.lock().unwrap();
-        map.entry(barcode_indices.to_vec()).or_insert_with(UmiCounter::new).add(umi);
+        for (barcode, umi_counter) in other.map.into_inner().unwrap() {
+            match map.get(&barcode) {
+                Some(counter) => counter.merge(umi_counter),
+                None => {
+                    map.insert(barcode, umi_counter);
+                }
+            }
+        }
     }
 
-    pub fn write_barcode_stats(&self, filename: &str) -> std::io::Result<()> {
+    /// Writes the UMI statistics per barcode combination, identified by its
+    /// combination id, its sequence and the labels of its named barcodes
+    pub fn write_barcode_stats(&self, filename: &str, config: &Config) -> std::io::Result<()> {
         let mut writer = File::create(filename).map(BufWriter::new)?;
-        writer.write(b"barcode,total_umi,unique_umi,mean_umi,median_umi,q25,q75\n")?;
-        for (barcode, umi_counter) in self.map.lock().unwrap().iter() {
-            //let barcode_str = barcode.iter().map(|&idx| idx.to_string()).collect::<Vec<_>>().join("_");
-            let barcode_nr = Self::barcodes2u32(barcode);
+        writer.write_all(b"barcode,sequence,wells,total_umi,unique_umi,mean_umi,median_umi,q25,q75\n")?;
+        let map = self.map.lock().unwrap();
+        let mut barcodes = map
+            .iter()
+            .map(|(barcode, umi_counter)| (config.combination_id(barcode), barcode, umi_counter))
+            .collect::<Vec<_>>();
+        barcodes.sort_unstable_by_key(|(id, _, _)| *id);
+        for (barcode_nr, barcode, umi_counter) in barcodes {
+            let sequence = String::from_utf8_lossy(&config.build_barcode(barcode)).into_owned();
+            // the sequence identifies the barcodes of rounds without names
+            let wells = barcode
+                .iter()
+                .enumerate()
+                .filter(|&(position, &idx)| config.get_name(idx, position).is_some())
+                .map(|(position, &idx)| config.label(idx, position))
+                .collect::<Vec<_>>()
+                .join(";");
+            let wells = csv_field(&wells);
             let umi_counts: Vec<u32> = umi_counter.map.lock().unwrap().values().cloned().collect();
             let total_umis = umi_counts.iter().sum::<u32>();
             let unique_umis = umi_counts.len() as u32;
@@ -292,11 +472,14 @@ impl BarcodeUmiCounter {
             
             let mean_umi = total_umis as f64 / unique_umis as f64;
             let median_umi = sorted_counts[sorted_counts.len() / 2];
-            let q25 = sorted_counts[(sorted_counts.len() / 4) as usize];
-            let q75 = sorted_counts[(sorted_counts.len() * 3 / 4) as usize];
-    
-            writeln!(writer, "{},{},{},{},{:.1},{},{}", barcode_nr, total_umis, unique_umis,  mean_umi, median_umi, q25, q75)?;
+            let q25 = sorted_counts[sorted_counts.len() / 4];
+            let q75 = sorted_counts[sorted_counts.len() * 3 / 4];
     
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{:.1},{},{}",
+                barcode_nr, sequence, wells, total_umis, unique_umis, mean_umi, median_umi, q25, q75
+            )?;
         }
         Ok(())
     }
@@ -350,15 +533,25 @@ impl UMIBaseComposition {
         Self { bases }
     }
 
-    pub fn add(&mut self, umi: &Vec<u8>) {
+    pub fn add(&mut self, umi: &[u8]) {
         for (i, &base) in umi.iter().enumerate() {
             self.bases[i].add_base(base);
         }
     }
 
+    pub fn merge(&mut self, other: &Self) {
+        for (base, other) in self.bases.iter_mut().zip(&other.bases) {
+            base.a += other.a;
+            base.c += other.c;
+            base.g += other.g;
+            base.t += other.t;
+            base.n += other.n;
+        }
+    }
+
     pub fn write_umi_base_composition(&self, filename: &str) -> std::io::Result<()> {
         let mut writer = File::create(filename).map(BufWriter::new)?;
-        writer.write(b"position,a,c,g,t,n\n")?;
+        writer.write_all(b"position,a,c,g,t,n\n")?;
 
         for (i, base) in self.bases.iter().enumerate() {
             if ! base.empty() {
@@ -369,4 +562,73 @@ impl UMIBaseComposition {
         Ok(())
     }
     
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use std::path::Path;
+
+    /// Writes a whitelist of `size` distinct 10 nt barcodes, named by `name` if given
+    fn write_whitelist(path: &Path, size: usize, name: Option<&dyn Fn(usize) -> String>) {
+        let rows = (0..size)
+            .map(|i| {
+                let seq = (0..10).map(|k| b"ACGT"[(i >> (2 * k)) & 3] as char).collect::<String>();
+                match name {
+                    Some(name) => format!("{}\t{}\n", seq, name(i)),
+                    None => format!("{}\n", seq),
+                }
+            })
+            .collect::<String>();
+        std::fs::write(path, rows).unwrap();
+    }
+
+    #[test]
+    fn quote_csv_fields() {
+        assert_eq!(csv_field("bc1=A01;bc2=C07"), "bc1=A01;bc2=C07");
+        assert_eq!(csv_field("bc1=A01,B01"), "\"bc1=A01,B01\"");
+        assert_eq!(csv_field("bc1=\"A01\""), "\"bc1=\"\"A01\"\"\"");
+    }
+
+    #[test]
+    fn write_barcode_umi_stats() {
+        let dir = std::env::temp_dir().join("pipspeak_barcode_umi_stats");
+        std::fs::create_dir_all(&dir).unwrap();
+        for round in 1..=5 {
+            let path = dir.join(format!("bc{}.tsv", round));
+            match round {
+                1 => write_whitelist(&path, 300, Some(&|i| format!("well,{}", i))),
+                3 => write_whitelist(&path, 300, Some(&|i| format!("C{}", i))),
+                _ => write_whitelist(&path, 300, None),
+            }
+        }
+        let config_path = dir.join("config.yaml");
+        let barcodes = (1..=5).map(|round| format!("  bc{0}: bc{0}.tsv\n", round)).collect::<String>();
+        std::fs::write(&config_path, format!("barcodes:\n{}spacers: {{}}\n", barcodes)).unwrap();
+        let config = Config::from_file(config_path.to_str().unwrap(), true, false).unwrap();
+
+        let counter = BarcodeUmiCounter::new();
+        let last = [299, 0, 1, 2, 257];
+        counter.add(&last, b"AAAA");
+        counter.add(&last, b"AAAA");
+        counter.add(&last, b"CCCC");
+        counter.add(&[0; 5], b"AAAA");
+        let path = dir.join("barcode_umi_stats.tsv");
+        counter.write_barcode_stats(path.to_str().unwrap(), &config).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "barcode,sequence,wells,total_umi,unique_umi,mean_umi,median_umi,q25,q75");
+        let sequence = |indices: &[usize]| String::from_utf8(config.build_barcode(indices)).unwrap();
+        assert_eq!(sequence(&[0; 5]), "A".repeat(50));
+        assert_eq!(lines[1], format!("0,{},\"bc1=well,0;bc3=C0\",1,1,1,1,1,1", sequence(&[0; 5])));
+        // the id is lossless with more than 256 entries per round and more than 4 rounds
+        assert_eq!(sequence(&last).len(), 50);
+        let id = last.iter().fold(0u128, |id, &idx| id * 300 + idx as u128);
+        assert_eq!(
+            lines[2],
+            format!("{},{},\"bc1=well,299;bc3=C1\",3,2,1.5,2,1,2", id, sequence(&last))
+        );
+    }
+}
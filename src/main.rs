@@ -1,14 +1,27 @@
 mod barcodes;
+mod chemistry;
 mod cli;
 mod config;
+mod detect;
 mod log;
+mod orientation;
+mod packed;
 mod parser;
+mod paths;
+mod read_structure;
+mod seqspec;
+mod settings;
+mod validate;
+mod whitelist;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::Local;
+use barcodes::Tolerance;
 use clap::Parser;
-use cli::Cli;
-use config::Config;
+use chemistry::ChemistryInfo;
+use cli::{Cli, Command, ValidateArgs};
+use config::{Config, ConfigParameters, ConfigYaml};
+use indexmap::IndexMap;
 use fxread::initialize_reader;
 use gzp::{
     deflate::Gzip,
@@ -16,31 +29,68 @@ use gzp::{
 };
 
 
-use ::log::{LevelFilter, info, debug, error};
+use ::log::{LevelFilter, info, debug, error, warn};
 use env_logger::Builder;
 use log::{FileIO, Log, Parameters, Timing};
+use orientation::OrientationOptions;
+use read_structure::ReadStructure;
+use seqspec::Construct;
+use settings::{Setting, Settings};
 use std::{
     fs::File,
+    path::Path,
     time::Instant,
 };
 
 
-use crate::parser::parse_records;
+use crate::parser::{parse_records, round_window};
 
+/// Barcodes of each round listed with their labels in the log
+const TOP_BARCODES: usize = 5;
 
-/// Sets the number of threads to use for writing R1 and R2 files
-fn set_threads(num_threads: usize) -> (usize, usize) {
+/// Splits the threads between matching reads (the larger half) and
+/// compressing the R1 and R2 files, with at least one thread each
+fn split_threads(num_threads: usize) -> (usize, usize, usize) {
     if num_threads == 0 {
-        set_threads(num_cpus::get())
-    } else if num_threads == 1 {
-        (1, 1)
-    } else {
-        if num_threads % 2 == 0 {
-            (num_threads / 2, num_threads / 2)
-        } else {
-            (num_threads / 2, num_threads / 2 + 1)
-        }
+        return split_threads(num_cpus::get());
+    }
+    let compression = num_threads / 2;
+    let matching = num_threads - compression;
+    (matching, (compression / 2).max(1), (compression - compression / 2).max(1))
+}
+
+/// Parses the onlists of the read structure given as NAME=PATH
+fn parse_onlists(onlists: &[String]) -> Result<IndexMap<String, String>> {
+    onlists
+        .iter()
+        .map(|onlist| {
+            let (name, path) = onlist
+                .split_once('=')
+                .with_context(|| format!("Onlist '{}' is not given as NAME=PATH", onlist))?;
+            if chemistry::is_builtin(path) {
+                return Ok((name.to_string(), path.to_string()));
+            }
+            let path = paths::resolve(path, Path::new(""))?;
+            Ok((name.to_string(), path.to_string_lossy().into_owned()))
+        })
+        .collect()
+}
+
+/// Checks a config and its whitelists, exiting with status 1 on problems
+fn run_validate(args: &ValidateArgs) -> Result<()> {
+    let yaml = match (args.chemistry, &args.config) {
+        (Some(chemistry), config) => ConfigYaml::from_preset(chemistry, config.as_deref())?,
+        (None, Some(path)) => ConfigYaml::from_file(path)?,
+        (None, None) => anyhow::bail!("Either a config or a chemistry is required"),
+    };
+    let parameters = yaml.parameters();
+    let exact = Setting::resolve(false, parameters.exact, args.exact);
+    let report = validate::validate(&yaml, &Tolerance::new(exact.value, parameters.indels.unwrap_or(false)))?;
+    print!("{}", report);
+    if report.num_errors() > 0 || (args.strict && report.num_warnings() > 0) {
+        std::process::exit(1);
     }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -62,12 +112,85 @@ fn main() -> Result<()> {
     builder.filter_level(log_level);
     builder.init();
 
+    if let Some(Command::Validate(validate_args)) = &args.command {
+        return run_validate(validate_args);
+    }
     info!("Starting Pipspeak version {}", env!("CARGO_PKG_VERSION"));
     debug!("Arguments: {:?}", args);
+    let (Some(r1_path), Some(r2_path)) = (args.r1.clone(), args.r2.clone()) else {
+        anyhow::bail!("--r1 and --r2 are required");
+    };
 
-    let config = Config::from_file(&args.config, args.exact, args.linkers)?;
-    let r1 = initialize_reader(&args.r1)?;
-    let r2 = initialize_reader(&args.r2)?;
+    let read_structure = args
+        .read_structure
+        .as_deref()
+        .map(str::parse::<ReadStructure>)
+        .transpose()?;
+    let onlists = parse_onlists(&args.onlist)?;
+    let construct = args.seqspec.as_deref().map(Construct::from_file).transpose()?;
+    // the tolerance and linkers of a config are taken from its parameters
+    let load = |yaml: ConfigYaml| -> Result<Config> {
+        let settings = Settings::resolve(&args, &yaml.parameters())?;
+        Config::from_yaml(yaml, &settings.tolerance(), settings.linkers.value)
+    };
+    let defaults = Settings::resolve(&args, &ConfigParameters::default())?;
+    let mut chemistry = args.chemistry;
+    let mut config_file = args.config.clone().or(args.seqspec.clone());
+    let mut detection = None;
+    let config = if args.detect {
+        let candidates = detect::candidates(&args.candidate, load)?;
+        let sample = detect::sample(&r1_path, &r2_path, defaults.orientation.value, args.detect_sample)?;
+        let result = detect::detect(&sample, &candidates);
+        if !args.quiet {
+            eprint!("{}", result.table());
+        }
+        if args.detect_only {
+            return Ok(());
+        }
+        let winner = result.winner()?;
+        let candidate = candidates.into_iter().nth(winner).context("Invalid detection winner")?;
+        info!("Detected {} (confidence {:.2})", candidate.name, result.confidence);
+        chemistry = candidate.chemistry;
+        if chemistry.is_none() {
+            config_file = Some(candidate.name.clone());
+        }
+        detection = Some(result);
+        candidate.config
+    } else {
+        match (&read_structure, &construct, &args.config) {
+            (Some(structure), _, _) => {
+                info!("Read structure: {}", structure);
+                Config::from_read_structure(structure, &onlists, &defaults.tolerance(), defaults.linkers.value)?
+            }
+            (None, Some(construct), _) => {
+                Config::from_seqspec(construct, &onlists, &defaults.tolerance(), defaults.linkers.value)?
+            }
+            (None, None, config_path) => match args.chemistry {
+                Some(chemistry) => {
+                    info!("Chemistry preset: {} (version {})", chemistry.name(), chemistry.version());
+                    load(ConfigYaml::from_preset(chemistry, config_path.as_deref())?)?
+                }
+                None => match config_path {
+                    Some(path) => load(ConfigYaml::from_file(path)?)?,
+                    None => anyhow::bail!("Either a config, a chemistry, a read structure or a seqspec is required"),
+                },
+            },
+        }
+    };
+    let settings = Settings::resolve(&args, config.parameters())?;
+    settings.log()?;
+    let config = config.with_anchored(settings.anchored.value);
+    if config.has_stagger() {
+        info!("bc1 is placed by the stagger segment of the config, the offset is ignored");
+    }
+    if settings.anchored.value && config.umi_anchor().is_none() {
+        info!("No umi_anchor in the config, the UMI is placed by the umi_offset");
+    }
+    if config.umi_anchor_len().is_some() && settings.umi_offset.value > 0 {
+        warn!("The UMI directly follows the umi_anchor, the umi_offset is ignored");
+    }
+    let r1 = initialize_reader(&r1_path)?;
+    let r2 = initialize_reader(&r2_path)?;
 
     let r1_filename = args.prefix.clone() + "_R1.fq.gz";
     let r2_filename = args.prefix.clone() + "_R2.fq.gz";
@@ -76,8 +199,10 @@ fn main() -> Result<()> {
     let countermaps_filename = args.prefix.clone() + "_barcode_position_counts.tsv";
     let barcodes_umi_filename = args.prefix.clone() + "_barcode_umi_stats.tsv";
     let umi_stats_filename = args.prefix.clone() + "_umi_composition_stats.tsv";
+    let seqspec_filename = args.prefix.clone() + "_seqspec.yaml";
+    let output_seqspec_filename = args.prefix.clone() + "_output_seqspec.yaml";
 
-    let (r1_threads, r2_threads) = set_threads(args.threads);
+    let (match_threads, r1_threads, r2_threads) = split_threads(args.threads);
     let mut r1_writer: ParCompress<Gzip> = ParCompressBuilder::new()
         .num_threads(r1_threads)?
         .from_writer(File::create(&r1_filename)?);
@@ -88,23 +213,40 @@ fn main() -> Result<()> {
     let timestamp = Local::now().to_string();
     let start_time = Instant::now();
 
-    let umi_len = if config.umi_len() == 0 { args.umi_len }else{ config.umi_len() };
+    let offset = settings.offset.value;
+    let umi_len = settings.umi_len.value;
+    let umi_offset = settings.umi_offset.value;
 
-    let statistics = parse_records(
+    let mut statistics = parse_records(
         r1,
         r2,
         &mut r1_writer,
         &mut r2_writer,
         &config,
-        args.offset,
+        offset,
         umi_len,
-        args.umi_offset,
+        umi_offset,
+        OrientationOptions {
+            orientation: settings.orientation.value,
+            sample_size: settings.orientation_sample.value,
+            mixed: settings.mixed_orientation.value,
+        },
+        match_threads,
+        !args.unordered,
     )?;
     statistics.whitelist_to_file(&whitelist_filename)?;
     statistics.counter_maps_to_file(&countermaps_filename, &config)?;
-    statistics.barcode_umi_stats_to_file(&barcodes_umi_filename)?;
+    statistics.label_top_barcodes(&config, TOP_BARCODES);
+    statistics.barcode_umi_stats_to_file(&barcodes_umi_filename, &config)?;
     statistics.umi_base_composition.write_umi_base_composition(&umi_stats_filename)?;
 
+    let windows = (0..config.barcode_count())
+        .map(|i| round_window(&config, i, offset))
+        .collect::<Vec<_>>();
+    let effective_construct = config.construct(&windows, umi_len, umi_offset);
+    effective_construct.input_assay().to_file(&seqspec_filename)?;
+    effective_construct.output_assay(config.linkers()).to_file(&output_seqspec_filename)?;
+
     let elapsed_time = start_time.elapsed().as_secs_f64();
     let timing = Timing {
         timestamp,
@@ -112,16 +254,24 @@ fn main() -> Result<()> {
     };
 
     let parameters = Parameters {
-        offset: args.offset,
-        umi_len: args.umi_len,
-        exact_matching: args.exact,
-        write_linkers: args.linkers,
+        settings,
+        barcode_mismatches: config.mismatches(),
+        spacer_mismatches: config.spacer_mismatches(),
+        read_structure: read_structure.as_ref().map(ToString::to_string),
+        chemistry: chemistry.map(ChemistryInfo::from),
         pipspeak_version: env!("CARGO_PKG_VERSION").to_string(),
     };
 
+    let config_path = config_file
+        .as_deref()
+        .map(|path| paths::resolve(path, Path::new("")))
+        .transpose()?;
     let file_io = FileIO {
-        readpath_r1: args.r1,
-        readpath_r2: args.r2,
+        config_path: config_path.map(|path| path.to_string_lossy().into_owned()),
+        barcode_paths: config.paths().to_vec(),
+        stagger_onlist: config.stagger_onlist().map(String::from),
+        readpath_r1: r1_path,
+        readpath_r2: r2_path,
         writepath_r1: r1_filename,
         writepath_r2: r2_filename,
         whitelist_path: whitelist_filename,
@@ -129,6 +279,7 @@ fn main() -> Result<()> {
 
     let log = Log {
         parameters,
+        detection,
         timing,
         statistics,
         file_io,
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use fxread::Record;
+use serde::{Deserialize, Serialize};
+
+/// Which read carries the barcodes and in which direction
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Orientation {
+    /// Barcodes are read from the start of R1
+    #[default]
+    Forward,
+    /// R1 carries the barcodes reverse complemented
+    Reverse,
+    /// R1 and R2 are swapped
+    Swapped,
+    /// R1 and R2 are swapped and the barcodes are reverse complemented
+    SwappedReverse,
+}
+
+impl Orientation {
+    pub const ALL: [Self; 4] = [Self::Forward, Self::Reverse, Self::Swapped, Self::SwappedReverse];
+
+    /// Returns true if R2 carries the barcodes
+    pub fn is_swapped(&self) -> bool {
+        matches!(self, Self::Swapped | Self::SwappedReverse)
+    }
+
+    /// Returns true if the barcode read is reverse complemented
+    pub fn is_reverse(&self) -> bool {
+        matches!(self, Self::Reverse | Self::SwappedReverse)
+    }
+
+    /// Returns the barcode read and the cDNA read of a pair,
+    /// with the barcode read in forward direction
+    pub fn apply(&self, r1: Record, r2: Record) -> Result<(Record, Record)> {
+        let (barcode_read, cdna_read) = if self.is_swapped() { (r2, r1) } else { (r1, r2) };
+        if self.is_reverse() {
+            Ok((reverse_complement(&barcode_read)?, cdna_read))
+        } else {
+            Ok((barcode_read, cdna_read))
+        }
+    }
+}
+
+/// Settings for finding the barcodes on the reads
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OrientationOptions {
+    /// Fixed orientation, detected from a sample of reads if not set
+    pub orientation: Option<Orientation>,
+    /// Number of read pairs used for detection
+    pub sample_size: usize,
+    /// Retry reads that don't match with the barcode read reverse complemented
+    pub mixed: bool,
+}
+
+/// Returns the reverse complement of a record, reversing its qualities
+pub fn reverse_complement(record: &Record) -> Result<Record> {
+    let seq = record
+        .seq()
+        .iter()
+        .rev()
+        .map(|&b| match b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            _ => b'N',
+        })
+        .collect::<Vec<_>>();
+    match record.qual() {
+        Some(qual) => {
+            let qual = qual.iter().rev().copied().collect::<Vec<_>>();
+            Record::new_fastq_from_parts(record.id(), &seq, &qual)
+        }
+        None => Record::new_fasta_from_parts(record.id(), &seq),
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn reverse_complement_record() {
+        let record = Record::new_fastq_from_parts(b"id", b"AACGNT", b"123456").unwrap();
+        let rc = reverse_complement(&record).unwrap();
+        assert_eq!(rc.seq(), b"ANCGTT");
+        assert_eq!(rc.qual(), Some(&b"654321"[..]));
+        assert_eq!(rc.id(), b"id");
+    }
+
+    #[test]
+    fn apply_orientation() {
+        let pair = || {
+            (
+                Record::new_fastq_from_parts(b"r1", b"AAC", b"123").unwrap(),
+                Record::new_fastq_from_parts(b"r2", b"GGT", b"456").unwrap(),
+            )
+        };
+        let (r1, r2) = pair();
+        let (bc, cdna) = Orientation::Forward.apply(r1, r2).unwrap();
+        assert_eq!((bc.seq(), cdna.seq()), (&b"AAC"[..], &b"GGT"[..]));
+        let (r1, r2) = pair();
+        let (bc, cdna) = Orientation::SwappedReverse.apply(r1, r2).unwrap();
+        assert_eq!((bc.id(), bc.seq(), cdna.id()), (&b"r2"[..], &b"ACC"[..], &b"r1"[..]));
+    }
+}
@@ -0,0 +1,220 @@
+use hashbrown::HashMap;
+use std::cmp::Reverse;
+
+/// Longest sequence that fits into a packed key
+pub const MAX_PACKED_LEN: usize = 32;
+
+/// 2-bit codes of all bytes, 4 marks bytes that aren't nucleotides.
+/// Lower case bases aren't nucleotides, as sequences are compared case-sensitively.
+/// A table avoids mispredicted branches on random sequence
+const CODES: [u8; 256] = {
+    let mut codes = [4u8; 256];
+    codes[b'A' as usize] = 0;
+    codes[b'C' as usize] = 1;
+    codes[b'G' as usize] = 2;
+    codes[b'T' as usize] = 3;
+    codes
+};
+
+/// Returns the 2-bit code of a nucleotide
+pub fn encode_nucleotide(nucleotide: u8) -> Option<u64> {
+    match CODES[nucleotide as usize] {
+        4 => None,
+        code => Some(code as u64),
+    }
+}
+
+/// Packs a sequence into an integer with two bits per nucleotide,
+/// the first nucleotide in the most significant position.
+/// Returns None for sequences containing other bases or longer than 32 nt
+pub fn encode(sequence: &[u8]) -> Option<u64> {
+    if sequence.len() > MAX_PACKED_LEN {
+        return None;
+    }
+    sequence
+        .iter()
+        .try_fold(0u64, |code, &b| Some((code << 2) | encode_nucleotide(b)?))
+}
+
+type PackedTable<V> = HashMap<u64, V>;
+
+/// A map from packed sequences to values.
+/// Keys of different lengths are kept in separate tables, longest first
+#[derive(Debug)]
+pub struct PackedMap<V> {
+    tables: Vec<(usize, PackedTable<V>)>,
+}
+
+impl<V> Default for PackedMap<V> {
+    fn default() -> Self {
+        Self { tables: Vec::new() }
+    }
+}
+
+impl<V> PackedMap<V> {
+    /// Inserts a value and returns false if the key can't be packed
+    pub fn insert(&mut self, key: &[u8], value: V) -> bool {
+        let Some(code) = encode(key) else {
+            return false;
+        };
+        match self.tables.iter_mut().find(|(len, _)| *len == key.len()) {
+            Some((_, table)) => {
+                table.insert(code, value);
+            }
+            None => {
+                let mut table = PackedTable::default();
+                table.insert(code, value);
+                self.tables.push((key.len(), table));
+                self.tables.sort_unstable_by_key(|(len, _)| Reverse(*len));
+            }
+        }
+        true
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        self.get_packed(key.len(), encode(key)?)
+    }
+
+    /// Looks up an already packed key of a given length
+    pub fn get_packed(&self, len: usize, code: u64) -> Option<&V> {
+        self.tables
+            .iter()
+            .find(|(l, _)| *l == len)
+            .and_then(|(_, table)| table.get(&code))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.iter().map(|(_, table)| table.len()).sum()
+    }
+}
+
+/// A window of a sequence with its nucleotides packed
+#[derive(Debug, Clone, Copy)]
+pub struct PackedWindow {
+    pub pos: usize,
+    width: usize,
+    /// Nucleotides left in the sequence from `pos`
+    available: usize,
+    code: u64,
+    /// One bit per nucleotide that can't be packed
+    invalid: u64,
+}
+
+impl PackedWindow {
+    /// Returns the packed code of the first `len` nucleotides of the window
+    pub fn prefix(&self, len: usize) -> Option<u64> {
+        if len > self.available || len > self.width {
+            return None;
+        }
+        let shift = self.width - len;
+        if self.invalid >> shift != 0 {
+            return None;
+        }
+        Some(self.code >> (2 * shift))
+    }
+}
+
+/// Iterates over all start positions of a sequence, updating the packed
+/// window by a single nucleotide per step instead of encoding it anew
+pub struct PackedWindows<'a> {
+    sequence: &'a [u8],
+    width: usize,
+    code: u64,
+    invalid: u64,
+    next: usize,
+}
+
+impl<'a> PackedWindows<'a> {
+    /// Windows of `width` nucleotides (1 to 32)
+    pub fn new(sequence: &'a [u8], width: usize) -> Self {
+        assert!(width > 0 && width <= MAX_PACKED_LEN, "Invalid packed window width {}", width);
+        let mut windows = Self {
+            sequence,
+            width,
+            code: 0,
+            invalid: 0,
+            next: 0,
+        };
+        for _ in 1..width {
+            windows.consume();
+        }
+        windows
+    }
+
+    /// Shifts the next nucleotide into the window
+    fn consume(&mut self) {
+        // positions past the end are marked invalid
+        let code = self.sequence.get(self.next).map_or(4, |&b| CODES[b as usize]) as u64;
+        let (bits, invalid) = (code & 3, code >> 2);
+        let code_mask = u64::MAX >> (64 - 2 * self.width);
+        let invalid_mask = u64::MAX >> (64 - self.width);
+        self.code = ((self.code << 2) | bits) & code_mask;
+        self.invalid = ((self.invalid << 1) | invalid) & invalid_mask;
+        self.next += 1;
+    }
+}
+
+impl Iterator for PackedWindows<'_> {
+    type Item = PackedWindow;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.next + 1 - self.width;
+        if pos >= self.sequence.len() {
+            return None;
+        }
+        self.consume();
+        Some(PackedWindow {
+            pos,
+            width: self.width,
+            available: self.sequence.len() - pos,
+            code: self.code,
+            invalid: self.invalid,
+        })
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn encode_sequences() {
+        assert_eq!(encode(b"A"), Some(0));
+        assert_eq!(encode(b"ACGT"), Some(0b00011011));
+        assert_eq!(encode(b"acgt"), None);
+        assert_eq!(encode(b"ACgT"), None);
+        assert_eq!(encode(b"ACNT"), None);
+        assert_eq!(encode(&[b'T'; 32]), Some(u64::MAX));
+        assert_eq!(encode(&[b'T'; 33]), None);
+    }
+
+    #[test]
+    fn packed_map() {
+        let mut map = PackedMap::default();
+        assert!(map.insert(b"ACGT", 1));
+        assert!(map.insert(b"ACG", 2));
+        assert!(!map.insert(b"ANG", 3));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(b"ACGT"), Some(&1));
+        assert_eq!(map.get(b"ACG"), Some(&2));
+        assert_eq!(map.get(b"AACG"), None);
+        assert_eq!(map.get(b"acgt"), None);
+    }
+
+    #[test]
+    fn rolling_windows() {
+        let sequence = b"ACGTNACGTTGCA";
+        for width in [1, 4, 6] {
+            for window in PackedWindows::new(sequence, width) {
+                for len in 1..=width {
+                    let expected = sequence.get(window.pos..window.pos + len).and_then(encode);
+                    assert_eq!(window.prefix(len), expected, "pos {} len {}", window.pos, len);
+                }
+            }
+        }
+        assert_eq!(PackedWindows::new(sequence, 4).count(), sequence.len());
+        let long = [b'G'; 40];
+        let window = PackedWindows::new(&long, 32).nth(3).unwrap();
+        assert_eq!(window.prefix(32), encode(&long[3..35]));
+    }
+}
@@ -1,7 +1,16 @@
 use std::{
-    io::Write, time::Duration
+    collections::BTreeMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Mutex,
+    },
+    thread,
+    time::Duration,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
+use log::{info, warn};
 use psutil::process::Process;
 use fxread::{FastxRead, Record};
 use indicatif::ProgressBar;
@@ -10,26 +19,203 @@ use gzp::{
     par::compress::ParCompress,
 };
 
+use crate::barcodes::BarcodeMatch;
 use crate::log::Statistics;
 use crate::config::Config;
+use crate::orientation::{reverse_complement, Orientation, OrientationOptions};
 
-fn match_records(rec1: &Record, offset: usize, config: &Config, statistics: &mut Statistics) -> Option<(usize, Vec<usize>)> {
+/// Maximum shift of rounds after the first one that don't set their own window.
+/// v2 had ambiguous bases in the spacers, which are handled by spacer lists now
+const DEFAULT_MAX_SHIFT: usize = 2;
+
+/// Minimum fraction of sampled reads matching in the detected orientation
+const MIN_ORIENTATION_FRACTION: f64 = 0.05;
+
+/// Read pairs per batch handed to a worker
+const BATCH_SIZE: usize = 4096;
+
+/// Nucleotides the UMI anchor may be shifted from the end of the last barcode
+const UMI_ANCHOR_SLACK: usize = 2;
+
+/// Why the barcodes of a read could not be assigned, with the failing round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejection {
+    Filtered(usize),
+    Ambiguous(usize),
+}
+
+/// The barcodes of a read that matched in every round
+#[derive(Debug)]
+struct ReadMatch {
+    /// Position after the last barcode
+    pos: usize,
+    matches: Vec<BarcodeMatch>,
+    /// Start and end of each round in the read
+    spans: Vec<(usize, usize)>,
+    stagger_len: Option<usize>,
+}
+
+impl ReadMatch {
+    /// Returns the barcode index of each round
+    fn indices(&self) -> Vec<usize> {
+        self.matches.iter().map(|m| m.id).collect()
+    }
+}
+
+/// Returns the minimum and maximum shift of a round. The maximum shift of bc1
+/// is the resolved `offset`, which a `max_shift` of bc1 in the config sets
+pub fn round_window(config: &Config, set_idx: usize, offset: usize) -> (usize, usize) {
+    if set_idx == 0 {
+        let (min_shift, _) = config.window(0, offset);
+        (min_shift, offset.max(min_shift))
+    } else {
+        config.window(set_idx, DEFAULT_MAX_SHIFT)
+    }
+}
+
+/// Matches all barcode rounds of a read without recording statistics
+fn find_matches(rec1: &Record, offset: usize, config: &Config) -> Result<ReadMatch, Rejection> {
     let mut pos = 0;
-    let mut barcode_indices = Vec::new();
-    let default_offset = Some(2); //because v2 had ambigous bases in spacer i added a default offset and cut off the last spacer base, then i imnplemented spacer lists, so this is not really necessary anymore
+    let mut matches = Vec::with_capacity(config.barcode_count());
+    let mut spans = Vec::with_capacity(config.barcode_count());
+    let mut stagger_len = None;
 
     for i in 0..config.barcode_count() {
-        if let Some((new_pos, bc_idx)) = config.match_subsequence(rec1.seq(), i, pos, if i == 0 { Some(offset) } else { default_offset }) {
-            pos = pos+new_pos;
-            barcode_indices.push(bc_idx);
+        let found = if i == 0 && config.has_stagger() {
+            config.match_staggered(rec1.seq(), rec1.qual()).map(|(m, len)| {
+                stagger_len = Some(len);
+                // the match ends at its position in the read
+                spans.push((config.stagger_start() + len + m.start, m.end));
+                m
+            })
         } else {
+            config.match_round(rec1.seq(), rec1.qual(), i, pos, round_window(config, i, offset)).inspect(|m| {
+                spans.push((pos + m.start, pos + m.end));
+            })
+        };
+        match found {
+            Some(m) if m.ambiguous => return Err(Rejection::Ambiguous(i)),
+            Some(m) => {
+                pos += m.end;
+                matches.push(m);
+            }
+            None => return Err(Rejection::Filtered(i)),
+        }
+    }
+    Ok(ReadMatch { pos, matches, spans, stagger_len })
+}
+
+/// Returns the shift of bc1 and the position after the last barcode
+/// of a read whose barcodes match in every round
+pub fn barcode_span(rec1: &Record, offset: usize, config: &Config) -> Option<(usize, usize)> {
+    find_matches(rec1, offset, config)
+        .ok()
+        .map(|read_match| (read_match.matches[0].start, read_match.pos))
+}
+
+/// Records the outcome of matching a read and returns the match of a passing read
+fn record_matches(found: Result<ReadMatch, Rejection>, statistics: &mut Statistics) -> Option<ReadMatch> {
+    let read_match = match found {
+        Ok(read_match) => read_match,
+        Err(Rejection::Filtered(i)) => {
             statistics.num_filtered[i] += 1;
             return None;
         }
+        Err(Rejection::Ambiguous(i)) => {
+            statistics.num_ambiguous[i] += 1;
+            return None;
+        }
+    };
+
+    statistics.add_matches(&read_match.matches);
+    if let Some(len) = read_match.stagger_len {
+        *statistics.stagger_lengths.entry(len).or_default() += 1;
     }
-    
     statistics.passing_reads += 1;
-    Some((pos, barcode_indices))
+    Some(read_match)
+}
+
+fn match_records(rec1: &Record, offset: usize, config: &Config, statistics: &mut Statistics) -> Option<ReadMatch> {
+    record_matches(find_matches(rec1, offset, config), statistics)
+}
+
+/// Matches a read and, if it fails, its reverse complement.
+/// Returns the record the barcodes were found on
+fn match_either_strand(rec1: Record, offset: usize, config: &Config) -> Result<(Record, Result<ReadMatch, Rejection>, bool)> {
+    let found = find_matches(&rec1, offset, config);
+    if found.is_ok() {
+        return Ok((rec1, found, false));
+    }
+    let reversed = reverse_complement(&rec1)?;
+    match find_matches(&reversed, offset, config) {
+        Ok(read_match) => Ok((reversed, Ok(read_match), true)),
+        Err(_) => Ok((rec1, found, false)),
+    }
+}
+
+/// Tries every orientation on a sample of read pairs and returns the one
+/// under which most reads match. Fails if no orientation matches enough reads
+fn detect_orientation(sample: &[(Record, Record)], offset: usize, config: &Config) -> Result<Orientation> {
+    if sample.is_empty() {
+        return Ok(Orientation::default());
+    }
+    let mut counts = Vec::with_capacity(Orientation::ALL.len());
+    for orientation in Orientation::ALL {
+        let mut count = 0;
+        for (r1, r2) in sample {
+            let barcode_read = if orientation.is_swapped() { r2 } else { r1 };
+            let found = if orientation.is_reverse() {
+                find_matches(&reverse_complement(barcode_read)?, offset, config)
+            } else {
+                find_matches(barcode_read, offset, config)
+            };
+            count += usize::from(found.is_ok());
+        }
+        counts.push((orientation, count));
+    }
+
+    let summary = counts
+        .iter()
+        .map(|(orientation, count)| format!("{:?}: {}/{}", orientation, count, sample.len()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!("Matching reads per orientation: {}", summary);
+
+    // ties keep the earlier orientation, forward first
+    let (orientation, count) = counts
+        .iter()
+        .rev()
+        .copied()
+        .max_by_key(|&(_, count)| count)
+        .unwrap_or_default();
+    if (count as f64) < MIN_ORIENTATION_FRACTION * sample.len() as f64 {
+        anyhow::bail!(
+            "Barcodes were found in too few of the first {} reads in any orientation ({}). \
+            Check the config and the input files, or set the orientation with --orientation",
+            sample.len(),
+            summary
+        );
+    }
+    if orientation != Orientation::Forward {
+        warn!("Reads appear to be in {:?} orientation ({}), continuing with this orientation", orientation, summary);
+    }
+    Ok(orientation)
+}
+
+/// Returns the start of the UMI: behind the UMI anchor, which is located in
+/// anchored mode and expected directly after the last barcode otherwise,
+/// or `umi_offset` after the last barcode without anchor
+fn locate_umi(rec1: &Record, pos: usize, umi_offset: usize, config: &Config, statistics: &mut Statistics) -> Option<usize> {
+    let Some(anchor) = config.umi_anchor() else {
+        return Some(pos + config.umi_anchor_len().unwrap_or(umi_offset));
+    };
+    match anchor.find(rec1.seq(), pos, pos + UMI_ANCHOR_SLACK) {
+        Some(hit) => Some(hit.end),
+        None => {
+            statistics.num_filtered_umi += 1;
+            None
+        }
+    }
 }
 
 fn match_umi(rec1: &Record, pos: usize, umi_len: usize, umi_offset: usize, statistics: &mut Statistics) -> Option<(usize, Vec<u8>)> {
@@ -38,7 +224,7 @@ fn match_umi(rec1: &Record, pos: usize, umi_len: usize, umi_offset: usize, stati
         None
     } else {
         let umi = rec1.seq()[pos + umi_offset .. pos + umi_offset + umi_len].to_vec();
-        let contains_n = umi.iter().any(|&base| base == b'N');
+        let contains_n = umi.contains(&b'N');
         if contains_n {
             statistics.num_filtered_umi += 1;
             None
@@ -48,16 +234,33 @@ fn match_umi(rec1: &Record, pos: usize, umi_len: usize, umi_offset: usize, stati
     }
 }
 
-fn construct_match(rec1: &Record, pos: usize, barcode_indices: &[usize], umi: &Vec<u8>, config: &Config, statistics: &mut Statistics) -> (Vec<u8>, Vec<u8>) {
-    let mut construct_seq = config.build_barcode(barcode_indices);
+/// Fits the qualities of a matched read span to the length of its output
+/// sequence. Deletions are padded with the lowest quality of the span
+fn fit_quality(qual: &[u8], len: usize) -> Vec<u8> {
+    let mut fitted = qual[..len.min(qual.len())].to_vec();
+    fitted.resize(len, qual.iter().min().copied().unwrap_or(b'!'));
+    fitted
+}
+
+/// Builds the output barcode and UMI of a passing read with the qualities
+/// of the read positions they were matched at
+fn construct_match(rec1: &Record, read_match: &ReadMatch, umi_start: usize, umi: &[u8], config: &Config, statistics: &mut Statistics) -> (Vec<u8>, Vec<u8>) {
+    let barcode_indices = read_match.indices();
+    let mut construct_seq = config.build_barcode(&barcode_indices);
     for (i, &idx) in barcode_indices.iter().enumerate() {
         statistics.counter_maps.add(idx, i);
     }
-    statistics.barcode_umi_counter.add(barcode_indices, umi);
+    statistics.barcode_umi_counter.add(&barcode_indices, umi);
     statistics.umi_base_composition.add(umi);
     construct_seq.extend_from_slice(umi);
-    
-    let construct_qual = rec1.qual().unwrap()[pos - construct_seq.len()..pos].to_vec();
+
+    let qual = rec1.qual().unwrap();
+    let mut construct_qual = Vec::with_capacity(construct_seq.len());
+    for (i, (&idx, &(start, end))) in barcode_indices.iter().zip(&read_match.spans).enumerate() {
+        let len = config.get_barcode(idx, i).map_or(0, |bc| bc.len());
+        construct_qual.extend(fit_quality(&qual[start..end], len));
+    }
+    construct_qual.extend_from_slice(&qual[umi_start..umi_start + umi.len()]);
     (construct_seq, construct_qual)
 }
 
@@ -70,6 +273,144 @@ fn processed_message(idx: usize) -> String {
     msg
 }
 
+/// Settings for processing a read pair, shared by all workers
+#[derive(Debug, Clone, Copy)]
+struct ReadSettings {
+    offset: usize,
+    umi_len: usize,
+    umi_offset: usize,
+    orientation: Orientation,
+    mixed_orientation: bool,
+}
+
+/// Consecutive read pairs processed by a worker
+struct Batch {
+    idx: usize,
+    pairs: Vec<(Record, Record)>,
+}
+
+/// The fastq records of a batch's passing reads
+struct BatchOutput {
+    idx: usize,
+    r1: Vec<u8>,
+    r2: Vec<u8>,
+}
+
+/// Matches a read pair and writes the construct and R2 of a passing read
+fn process_pair<W: Write>(
+    rec1: Record,
+    rec2: Record,
+    config: &Config,
+    settings: &ReadSettings,
+    statistics: &mut Statistics,
+    r1_out: &mut W,
+    r2_out: &mut W,
+) -> Result<()> {
+    statistics.total_reads += 1;
+    if rec1.qual().is_none() || rec2.qual().is_none() {
+        bail!("Read {} has no qualities, only FASTQ input is supported", String::from_utf8_lossy(rec1.id()));
+    }
+    let (rec1, rec2) = settings.orientation.apply(rec1, rec2)?;
+
+    let (rec1, matched) = if settings.mixed_orientation {
+        let (rec1, found, reversed) = match_either_strand(rec1, settings.offset, config)?;
+        let matched = record_matches(found, statistics);
+        if matched.is_some() && reversed {
+            statistics.num_reverse_complement += 1;
+        }
+        (rec1, matched)
+    } else {
+        let matched = match_records(&rec1, settings.offset, config, statistics);
+        (rec1, matched)
+    };
+
+    let Some(read_match) = matched else {
+        return Ok(());
+    };
+    let Some(pos) = locate_umi(&rec1, read_match.pos, settings.umi_offset, config, statistics) else {
+        return Ok(());
+    };
+    if let Some((pos, umi)) = match_umi(&rec1, pos, settings.umi_len, 0, statistics) {
+        let (c_seq, c_qual) = construct_match(&rec1, &read_match, pos - umi.len(), &umi, config, statistics);
+
+        statistics.whitelist.insert(c_seq.clone());
+        write_to_fastq(r1_out, rec1.id(), &c_seq, &c_qual)?;
+        write_to_fastq(r2_out, rec2.id(), rec2.seq(), rec2.qual().unwrap())?;
+    }
+    Ok(())
+}
+
+/// Stops the reader after an error and discards the batches it already
+/// sent, so that it isn't blocked on a full channel
+fn cancel(batches: &Mutex<Receiver<Batch>>, cancelled: &AtomicBool) {
+    cancelled.store(true, Ordering::Relaxed);
+    while batches.lock().unwrap().recv().is_ok() {}
+}
+
+/// Processes batches until the reader is done and returns the worker's statistics
+fn run_worker(
+    batches: &Mutex<Receiver<Batch>>,
+    outputs: SyncSender<BatchOutput>,
+    config: &Config,
+    settings: &ReadSettings,
+    cancelled: &AtomicBool,
+) -> Result<Statistics> {
+    let mut statistics = Statistics::new(config.barcode_count(), settings.umi_len);
+    loop {
+        // the lock is released before the batch is processed
+        let batch = batches.lock().unwrap().recv();
+        let Ok(batch) = batch else {
+            break;
+        };
+        let mut output = BatchOutput {
+            idx: batch.idx,
+            r1: Vec::new(),
+            r2: Vec::new(),
+        };
+        for (rec1, rec2) in batch.pairs {
+            if let Err(error) = process_pair(rec1, rec2, config, settings, &mut statistics, &mut output.r1, &mut output.r2) {
+                cancel(batches, cancelled);
+                return Err(error);
+            }
+        }
+        // the writer failed and reports its error
+        if outputs.send(output).is_err() {
+            cancel(batches, cancelled);
+            break;
+        }
+    }
+    Ok(statistics)
+}
+
+/// Writes the batches, in input order unless `ordered` is false
+fn run_writer<W: Write>(
+    outputs: Receiver<BatchOutput>,
+    r1_out: &mut W,
+    r2_out: &mut W,
+    ordered: bool,
+) -> Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    for output in outputs {
+        if !ordered {
+            r1_out.write_all(&output.r1)?;
+            r2_out.write_all(&output.r2)?;
+            continue;
+        }
+        pending.insert(output.idx, output);
+        while let Some(output) = pending.remove(&next) {
+            r1_out.write_all(&output.r1)?;
+            r2_out.write_all(&output.r2)?;
+            next += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Matches all read pairs with a reader -> worker pool -> writer pipeline.
+/// With `ordered` the output is in the order of the input, identical to a
+/// single threaded run
+#[allow(clippy::too_many_arguments)]
 pub fn parse_records(
     r1: Box<dyn FastxRead<Item = Record>>,
     r2: Box<dyn FastxRead<Item = Record>>,
@@ -79,32 +420,123 @@ pub fn parse_records(
     offset: usize,
     umi_len: usize,
     umi_offset: usize,
+    orientation_options: OrientationOptions,
+    threads: usize,
+    ordered: bool,
+) -> Result<Statistics> {
+    parse_batches(
+        r1,
+        r2,
+        r1_out,
+        r2_out,
+        config,
+        offset,
+        umi_len,
+        umi_offset,
+        orientation_options,
+        threads,
+        ordered,
+        BATCH_SIZE,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn parse_batches<W: Write + Send>(
+    r1: Box<dyn FastxRead<Item = Record>>,
+    r2: Box<dyn FastxRead<Item = Record>>,
+    r1_out: &mut W,
+    r2_out: &mut W,
+    config: &Config,
+    offset: usize,
+    umi_len: usize,
+    umi_offset: usize,
+    orientation_options: OrientationOptions,
+    threads: usize,
+    ordered: bool,
+    batch_size: usize,
 ) -> Result<Statistics> {
     let pb = ProgressBar::new_spinner();
     pb.enable_steady_tick(Duration::from_millis(100));
-    let mut statistics = Statistics::new(config.barcode_count());
+    let mut statistics = Statistics::new(config.barcode_count(), umi_len);
+
+    let mut records = r1.zip(r2);
+    let mut sample = Vec::new();
+    statistics.orientation = match orientation_options.orientation {
+        Some(orientation) => orientation,
+        None => {
+            sample.extend(records.by_ref().take(orientation_options.sample_size));
+            detect_orientation(&sample, offset, config)?
+        }
+    };
+    let record_iter = sample.into_iter().chain(records).enumerate();
 
-    let record_iter = r1.zip(r2).enumerate();
+    let settings = ReadSettings {
+        offset,
+        umi_len,
+        // in anchored mode the UMI starts directly after its anchor
+        umi_offset: if config.umi_anchor().is_some() { 0 } else { umi_offset },
+        orientation: statistics.orientation,
+        mixed_orientation: orientation_options.mixed,
+    };
+    let threads = threads.max(1);
 
-    for (idx, (rec1, rec2)) in record_iter {
-        statistics.total_reads += 1;
+    let (batch_tx, batch_rx) = mpsc::sync_channel::<Batch>(2 * threads);
+    let (output_tx, output_rx) = mpsc::sync_channel::<BatchOutput>(2 * threads);
+    let batch_rx = Mutex::new(batch_rx);
+    let cancelled = AtomicBool::new(false);
 
-        if idx % 1000000 == 0 || (idx < 1000 && idx % 100 == 0) {
-            let msg = processed_message(idx);
-            print!("{}", msg);
-            pb.set_message(msg);
-        }
+    let (worker_results, writer_result) = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                let output_tx = output_tx.clone();
+                scope.spawn(|| run_worker(&batch_rx, output_tx, config, &settings, &cancelled))
+            })
+            .collect::<Vec<_>>();
+        drop(output_tx);
+        let writer = scope.spawn(|| {
+            let result = run_writer(output_rx, r1_out, r2_out, ordered);
+            if result.is_err() {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+            result
+        });
 
-        if let Some((pos, barcode_indices)) = match_records(&rec1, offset, config, &mut statistics) {
-            if let Some((pos, umi)) = match_umi(&rec1, pos, umi_len, umi_offset, &mut statistics) {
-                let (c_seq, c_qual) = construct_match(&rec1, pos, &barcode_indices, &umi, config, &mut statistics);
-                
-                statistics.whitelist.insert(c_seq.clone());
-                write_to_fastq(r1_out, rec1.id(), &c_seq, &c_qual)?;
-                write_to_fastq(r2_out, rec2.id(), rec2.seq(), rec2.qual().unwrap())?;
+        // the reader runs on this thread
+        let mut batch = Batch { idx: 0, pairs: Vec::with_capacity(batch_size) };
+        for (idx, pair) in record_iter {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
             }
+            if idx % 1000000 == 0 || (idx < 1000 && idx % 100 == 0) {
+                let msg = processed_message(idx);
+                print!("{}", msg);
+                pb.set_message(msg);
+            }
+            batch.pairs.push(pair);
+            if batch.pairs.len() == batch_size {
+                let next = Batch { idx: batch.idx + 1, pairs: Vec::with_capacity(batch_size) };
+                if batch_tx.send(std::mem::replace(&mut batch, next)).is_err() {
+                    break;
+                }
+            }
+        }
+        if !batch.pairs.is_empty() && !cancelled.load(Ordering::Relaxed) {
+            let _ = batch_tx.send(batch);
         }
+        drop(batch_tx);
+
+        let worker_results = workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Worker thread panicked"))
+            .collect::<Vec<_>>();
+        let writer_result = writer.join().expect("Writer thread panicked");
+        (worker_results, writer_result)
+    });
+
+    for worker_statistics in worker_results {
+        statistics.merge(worker_statistics?);
     }
+    writer_result?;
 
     statistics.calculate_metrics();
     pb.finish_with_message(format!(
@@ -114,8 +546,6 @@ pub fn parse_records(
         statistics.fraction_passing * 100.0
     ));
     Ok(statistics)
-
-
 }
 
 
@@ -136,17 +566,27 @@ fn write_to_fastq<W: Write>(writer: &mut W, id: &[u8], seq: &[u8], qual: &[u8])
 mod testing {
 
     use super::*;
+    use crate::barcodes::Tolerance;
+    use crate::log::UmiKey;
+    use crate::read_structure::ReadStructure;
+    use crate::seqspec::Construct;
+    use indexmap::IndexMap;
 
     const TEST_PATH: &str = "data/config_v3.yaml";
 
+    /// Returns the position after the last barcode and the barcode indices of a passing read
+    fn matched(rec1: &Record, offset: usize, config: &Config, statistics: &mut Statistics) -> Option<(usize, Vec<usize>)> {
+        match_records(rec1, offset, config, statistics).map(|m| (m.pos, m.indices()))
+    }
+
     #[test]
     fn parse_v3() {
         let config = Config::from_file(TEST_PATH, false, false).unwrap();
-        let mut statistics = Statistics::new(config.barcode_count());
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
         let seq = b"NATACTGAATATGGTAATCGAGATCTGATCGAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC".to_vec();
         let qual = b"1".repeat(72).to_vec();
         let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap();
-        let result_record = match_records(&fastq, 5, &config, &mut statistics);
+        let result_record = matched(&fastq, 5, &config, &mut statistics);
         assert_eq!(result_record, Some((41, vec![41, 95, 70, 18])));
         assert_eq!(statistics.passing_reads, 1);
         let result_umi = match_umi(&fastq, 41, 12, 0,&mut statistics);
@@ -155,8 +595,369 @@ mod testing {
         assert_eq!(result_umi_4, Some((57, b"ACTTCGAGTGTG".to_vec())));
         assert_eq!(statistics.num_filtered_umi, 0);
         let result_seq = b"TACTGAATGTAATCATCTGAGAAAGACAGTACACTTCGAG".to_vec();
-        let (seq, qual) = construct_match(&fastq, 55, &result_record.unwrap().1, &result_umi.unwrap().1, &config, &mut statistics);
+        let read_match = match_records(&fastq, 5, &config, &mut statistics).unwrap();
+        let (seq, qual) = construct_match(&fastq, &read_match, 41, &result_umi.unwrap().1, &config, &mut statistics);
         assert_eq!(seq, result_seq);
         assert_eq!(qual, b"1".repeat(40).to_vec())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parse_v3_long_umi() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let mut statistics = Statistics::new(config.barcode_count(), 24);
+        let seq = b"NATACTGAATATGGTAATCGAGATCTGATCGAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC".to_vec();
+        let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &b"1".repeat(72)).unwrap();
+        let read_match = match_records(&fastq, 5, &config, &mut statistics).unwrap();
+        let (pos, umi) = match_umi(&fastq, read_match.pos, 24, 0, &mut statistics).unwrap();
+        assert_eq!(umi, b"GTACACTTCGAGTGTGATATCTGT".to_vec());
+        construct_match(&fastq, &read_match, pos - umi.len(), &umi, &config, &mut statistics);
+        assert_eq!(statistics.umi_base_composition.bases.len(), 24);
+        assert_eq!(statistics.umi_base_composition.bases[23].t, 1);
+        // UMIs of different lengths are counted apart
+        assert_ne!(UmiKey::new(b"ACGT"), UmiKey::new(b"AACGT"));
+        assert_ne!(UmiKey::new(&[b'C'; 32]), UmiKey::new(&[b'C'; 31]));
+    }
+
+    #[test]
+    fn parse_v3_deletion() {
+        let tolerance = Tolerance::new(false, true);
+        let config = Config::from_file_with_tolerance(TEST_PATH, &tolerance, false).unwrap();
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        // bc2 GTAATC is missing its fifth base
+        let seq = b"NATACTGAATATGGTAACGAGATCTGATCGAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC".to_vec();
+        let qual = b"1".repeat(71).to_vec();
+        let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap();
+        let result_record = matched(&fastq, 5, &config, &mut statistics);
+        assert_eq!(result_record, Some((40, vec![41, 95, 70, 18])));
+        let result_umi = match_umi(&fastq, 40, 12, 0, &mut statistics);
+        assert_eq!(result_umi, Some((52, b"GTACACTTCGAG".to_vec())));
+    }
+
+    #[test]
+    fn parse_v3_spacer_error() {
+        let config = Config::from_file("data/config_v3_mismatches.yaml", false, false).unwrap();
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        // bc3 ATCTGA and its spacer TCGAG both carry one error
+        let seq = b"NATACTGAATATGGTAATCGAGTTCTGATCCAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC".to_vec();
+        let qual = b"1".repeat(72).to_vec();
+        let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap();
+        let result_record = matched(&fastq, 5, &config, &mut statistics);
+        assert_eq!(result_record, Some((41, vec![41, 95, 70, 18])));
+        assert_eq!(statistics.num_corrected, vec![0, 0, 1, 0]);
+        assert_eq!(statistics.num_spacer_corrected, vec![0, 0, 1, 0]);
+    }
+
+    const VBCF_PATH: &str = "data/config_vbcf.yaml";
+
+    /// GTCA, phase block AGT, bc1-bc3 with their spacers, the TTCGAG anchor and a 14 nt UMI
+    fn vbcf_read(bc2: &[u8], s2: &[u8]) -> fxread::Record {
+        let mut seq = b"GTCAAGTTCCGTAGTAACC".to_vec();
+        seq.extend_from_slice(bc2);
+        seq.extend_from_slice(s2);
+        seq.extend_from_slice(b"ACGCTTGTCCTATTCGAGACGTACGTACGTACGGGGGGGG");
+        let qual = b"I".repeat(seq.len());
+        fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap()
+    }
+
+    #[test]
+    fn parse_anchored() {
+        let config = Config::from_file(VBCF_PATH, false, false).unwrap().with_anchored(true);
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        let fastq = vbcf_read(b"CTATGGAG", b"ACAG");
+        let result_record = matched(&fastq, 8, &config, &mut statistics);
+        assert_eq!(result_record, Some((43, vec![0, 0, 0])));
+        let pos = locate_umi(&fastq, 43, 0, &config, &mut statistics);
+        assert_eq!(pos, Some(49));
+        let result_umi = match_umi(&fastq, 49, 14, 0, &mut statistics);
+        assert_eq!(result_umi, Some((63, b"ACGTACGTACGTAC".to_vec())));
+    }
+
+    #[test]
+    fn parse_anchored_spacer_error() {
+        // one error in bc2 and one in its spacer exceed the shared budget
+        let fastq = vbcf_read(b"CTATGCAG", b"ACTG");
+        let config = Config::from_file(VBCF_PATH, false, false).unwrap();
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        assert_eq!(matched(&fastq, 8, &config, &mut statistics), None);
+        assert_eq!(statistics.num_filtered, vec![0, 1, 0]);
+
+        // but the spacer is located on its own in anchored mode
+        let config = config.with_anchored(true);
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        let result_record = matched(&fastq, 8, &config, &mut statistics);
+        assert_eq!(result_record, Some((43, vec![0, 0, 0])));
+        assert_eq!(statistics.num_corrected, vec![0, 1, 0]);
+        assert_eq!(statistics.num_spacer_corrected, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn parse_anchored_missing_umi_anchor() {
+        let config = Config::from_file(VBCF_PATH, false, false).unwrap().with_anchored(true);
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        let seq = b"GTCAAGTTCCGTAGTAACCCTATGGAGACAGACGCTTGTCCTAGGGGGGACGTACGTACGTAC".to_vec();
+        let qual = b"I".repeat(seq.len());
+        let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap();
+        assert_eq!(matched(&fastq, 8, &config, &mut statistics), Some((43, vec![0, 0, 0])));
+        assert_eq!(locate_umi(&fastq, 43, 0, &config, &mut statistics), None);
+        assert_eq!(statistics.num_filtered_umi, 1);
+    }
+
+    #[test]
+    fn parse_stagger() {
+        let config = Config::from_file(VBCF_PATH, false, false).unwrap();
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        for phase in [&b"AGT"[..], b"GT", b"T", b"GT"] {
+            let mut seq = b"GTCA".to_vec();
+            seq.extend_from_slice(phase);
+            seq.extend_from_slice(b"TCCGTAGTAACCCTATGGAGACAGACGCTTGTCCTATTCGAGACGTACGTACGTAC");
+            let qual = b"I".repeat(seq.len());
+            let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap();
+            let result_record = matched(&fastq, 0, &config, &mut statistics);
+            assert_eq!(result_record, Some((40 + phase.len(), vec![0, 0, 0])));
+        }
+        // CA is not part of the phase block onlist
+        let seq = b"GTCACATCCGTAGTAACCCTATGGAGACAGACGCTTGTCCTATTCGAG".to_vec();
+        let qual = b"I".repeat(seq.len());
+        let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap();
+        assert_eq!(matched(&fastq, 0, &config, &mut statistics), None);
+        assert_eq!(statistics.num_filtered, vec![1, 0, 0]);
+        assert_eq!(statistics.stagger_lengths.into_iter().collect::<Vec<_>>(), vec![(1, 1), (2, 2), (3, 1)]);
+    }
+
+    #[test]
+    fn parse_v3_shifted() {
+        // three nucleotides slipped in front of bc2
+        let seq = b"NATACTGAATATGCCCGTAATCGAGATCTGATCGAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC".to_vec();
+        let qual = b"1".repeat(75).to_vec();
+        let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap();
+
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        assert_eq!(matched(&fastq, 5, &config, &mut statistics), None);
+        assert_eq!(statistics.num_filtered, vec![0, 1, 0, 0]);
+
+        let config = Config::from_file("data/config_v3_shifts.yaml", false, false).unwrap();
+        assert_eq!(config.window(1, DEFAULT_MAX_SHIFT), (1, 3));
+        assert_eq!(config.window(2, DEFAULT_MAX_SHIFT), (0, 2));
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        let result_record = matched(&fastq, 5, &config, &mut statistics);
+        assert_eq!(result_record, Some((44, vec![41, 95, 70, 18])));
+        assert_eq!(statistics.shifts[0].get(&2), Some(&1));
+        assert_eq!(statistics.shifts[1].get(&3), Some(&1));
+        assert_eq!(statistics.shifts[2].get(&0), Some(&1));
+
+        // the unshifted read is outside of the bc2 window
+        let seq = b"NATACTGAATATGGTAATCGAGATCTGATCGAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC".to_vec();
+        let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual[..72]).unwrap();
+        assert_eq!(matched(&fastq, 5, &config, &mut statistics), None);
+    }
+
+    #[test]
+    fn parse_v3_ambiguous_placement() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let mut statistics = Statistics::new(config.barcode_count(), 12);
+        // bc1 TACTGAAT at shift 0 and AATATGAC at shift 5 are both exact
+        let seq = b"TACTGAATATGACATGGTAATCGAGATCTGATCGAGGAAAGACAGTACACTTCGAG".to_vec();
+        let qual = b"1".repeat(seq.len());
+        let fastq = fxread::Record::new_fastq_from_parts(b"id", &seq, &qual).unwrap();
+        assert_eq!(matched(&fastq, 5, &config, &mut statistics), None);
+        assert_eq!(statistics.num_ambiguous, vec![1, 0, 0, 0]);
+        assert_eq!(statistics.num_filtered, vec![0, 0, 0, 0]);
+    }
+
+    /// Runs a read pair through `process_pair` and returns the written R1 sequence and qualities
+    fn process_r1(rec1: Record, config: &Config, umi_len: usize, umi_offset: usize) -> Option<(Vec<u8>, Vec<u8>)> {
+        let settings = ReadSettings {
+            // the window of bc1 in the config, as resolved without --offset
+            offset: config.parameters().offset.unwrap_or(0),
+            umi_len,
+            umi_offset,
+            orientation: Orientation::Forward,
+            mixed_orientation: false,
+        };
+        let rec2 = Record::new_fastq_from_parts(b"id", b"ACGT", b"IIII").unwrap();
+        let mut statistics = Statistics::new(config.barcode_count(), umi_len);
+        let (mut r1_out, mut r2_out) = (Vec::new(), Vec::new());
+        process_pair(rec1, rec2, config, &settings, &mut statistics, &mut r1_out, &mut r2_out).unwrap();
+        let lines = r1_out.split(|&c| c == b'\n').collect::<Vec<_>>();
+        (lines.len() > 3).then(|| (lines[1].to_vec(), lines[3].to_vec()))
+    }
+
+    /// The VBCF whitelists by the given names of bc1 to bc3
+    fn vbcf_onlists(names: [&str; 3]) -> IndexMap<String, String> {
+        let paths = [
+            "configs/20260521/bc1_custom_primer.txt",
+            "configs/20260521/bc2.txt",
+            "configs/20260521/bc3.txt",
+        ];
+        names.iter().zip(paths).map(|(name, path)| (name.to_string(), path.to_string())).collect()
+    }
+
+    #[test]
+    fn construct_quality_deletion() {
+        let structure = "8B[bc1] 4S[AACC] 8B[bc2] 4S[ACAG] 8B[bc3] 4S[CCTA] 14U".parse::<ReadStructure>().unwrap();
+        let onlists = vbcf_onlists(["bc1", "bc2", "bc3"]);
+        let config = Config::from_read_structure(&structure, &onlists, &Tolerance::new(false, true), true).unwrap();
+        // bc1 TCCGTAGT at the start of the read is missing its third base
+        let seq = b"TCGTAGTAACCCTATGGAGACAGACGCTTGTCCTAACGTACGTACGTACGGGG";
+        let qual = (0..seq.len()).map(|i| b'!' + i as u8).collect::<Vec<_>>();
+        let rec1 = Record::new_fastq_from_parts(b"id", seq, &qual).unwrap();
+        let (out_seq, out_qual) = process_r1(rec1, &config, 14, 0).unwrap();
+        assert_eq!(out_seq, b"TCCGTAGTAACCCTATGGAGACAGACGCTTGTCCTAACGTACGTACGTAC");
+        assert_eq!(out_qual.len(), out_seq.len());
+        // bc1 is padded with the lowest quality of its span, the UMI keeps its own
+        assert_eq!(&out_qual[..12], &[&qual[..11], &qual[..1]].concat()[..]);
+        assert_eq!(&out_qual[12..36], &qual[11..35]);
+        assert_eq!(&out_qual[36..], &qual[35..49]);
+    }
+
+    /// A VBCF read without stagger: bc1-bc3 with their spacers, the TTCGAG anchor and the UMI
+    const VBCF_UNSTAGGERED: &[u8] = b"AGTCCGTAGTAACCCTATGGAGACAGACGCTTGTCCTATTCGAGACGTACGTACGTGGG";
+
+    #[test]
+    fn umi_after_anchor_read_structure() {
+        let structure = "0-3X 8B[bc1] 4S[AACC] 8B[bc2] 4S[ACAG] 8B[bc3] 4S[CCTA] 6S[TTCGAG] 12U"
+            .parse::<ReadStructure>()
+            .unwrap();
+        let onlists = vbcf_onlists(["bc1", "bc2", "bc3"]);
+        let config = Config::from_read_structure(&structure, &onlists, &Tolerance::new(false, false), false).unwrap();
+        let rec1 = || Record::new_fastq_from_parts(b"id", VBCF_UNSTAGGERED, &b"I".repeat(VBCF_UNSTAGGERED.len())).unwrap();
+        // the anchor is skipped without --anchored
+        let (seq, _) = process_r1(rec1(), &config, structure.umi_len, structure.umi_offset).unwrap();
+        assert_eq!(seq, b"TCCGTAGTCTATGGAGACGCTTGTACGTACGTACGT");
+        let config = config.with_anchored(true);
+        let (seq, _) = process_r1(rec1(), &config, structure.umi_len, structure.umi_offset).unwrap();
+        assert_eq!(seq, b"TCCGTAGTCTATGGAGACGCTTGTACGTACGTACGT");
+    }
+
+    #[test]
+    fn umi_after_anchor_seqspec() {
+        let construct = Construct::from_file("configs/20260521/vbcf_seqspec_20260521.yaml").unwrap();
+        let mut onlists = vbcf_onlists(["barcode1", "barcode2", "barcode3"]);
+        onlists.insert("phase_block".to_string(), "data/vbcf_phase_block.txt".to_string());
+        assert_eq!((construct.umi_len, construct.umi_offset), (14, 0));
+        // the UMI follows the anchor whether or not parsing is anchored
+        for anchored in [false, true] {
+            let config = Config::from_seqspec(&construct, &onlists, &Tolerance::new(false, false), false)
+                .unwrap()
+                .with_anchored(anchored);
+            let (seq, _) = process_r1(vbcf_read(b"CTATGGAG", b"ACAG"), &config, construct.umi_len, construct.umi_offset).unwrap();
+            assert_eq!(&seq[24..], b"ACGTACGTACGTAC");
+        }
+    }
+
+    fn pair(r1: &[u8], r2: &[u8]) -> (Record, Record) {
+        (
+            Record::new_fastq_from_parts(b"id", r1, &b"1".repeat(r1.len())).unwrap(),
+            Record::new_fastq_from_parts(b"id", r2, &b"1".repeat(r2.len())).unwrap(),
+        )
+    }
+
+    #[test]
+    fn detect_orientations() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let barcode_read = b"NATACTGAATATGGTAATCGAGATCTGATCGAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC";
+        let reversed = reverse_complement(&pair(barcode_read, b"").0).unwrap();
+        let cdna = b"TTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTT";
+
+        let sample = vec![pair(barcode_read, cdna)];
+        assert_eq!(detect_orientation(&sample, 5, &config).unwrap(), Orientation::Forward);
+        let sample = vec![pair(cdna, barcode_read)];
+        assert_eq!(detect_orientation(&sample, 5, &config).unwrap(), Orientation::Swapped);
+        let sample = vec![pair(cdna, reversed.seq())];
+        assert_eq!(detect_orientation(&sample, 5, &config).unwrap(), Orientation::SwappedReverse);
+        let sample = vec![pair(cdna, cdna)];
+        assert!(detect_orientation(&sample, 5, &config).is_err());
+    }
+
+    #[test]
+    fn match_mixed_orientation() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let barcode_read = b"NATACTGAATATGGTAATCGAGATCTGATCGAGGAAAGACAGTACACTTCGAGTGTGATATCTGTCTCTCTC";
+        let reversed = reverse_complement(&pair(barcode_read, b"").0).unwrap();
+        let (rec, found, is_reversed) = match_either_strand(reversed, 5, &config).unwrap();
+        assert!(is_reversed);
+        assert_eq!(rec.seq(), barcode_read);
+        assert_eq!(found.unwrap().pos, 41);
+
+        let (rec1, _) = pair(b"TTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTT", b"");
+        let (_, found, is_reversed) = match_either_strand(rec1, 5, &config).unwrap();
+        assert!(!is_reversed);
+        assert_eq!(found.unwrap_err(), Rejection::Filtered(0));
+    }
+
+    fn parse_example(threads: usize, batch_size: usize) -> (Vec<u8>, Vec<u8>, String) {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let r1 = fxread::initialize_reader("data/example_v3/example_R1.fq.gz").unwrap();
+        let r2 = fxread::initialize_reader("data/example_v3/example_R2.fq.gz").unwrap();
+        let (mut r1_out, mut r2_out) = (Vec::new(), Vec::new());
+        let options = OrientationOptions {
+            orientation: Some(Orientation::Forward),
+            ..Default::default()
+        };
+        let statistics = parse_batches(
+            r1, r2, &mut r1_out, &mut r2_out, &config, 5, 12, 0, options, threads, true, batch_size,
+        )
+        .unwrap();
+        (r1_out, r2_out, serde_yaml::to_string(&statistics).unwrap())
+    }
+
+    /// A writer for a full disk
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("no space left on device"))
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parse_failing_writer() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let options = OrientationOptions {
+            orientation: Some(Orientation::Forward),
+            ..Default::default()
+        };
+        for threads in [1, 4] {
+            let r1 = fxread::initialize_reader("data/example_v3/example_R1.fq.gz").unwrap();
+            let r2 = fxread::initialize_reader("data/example_v3/example_R2.fq.gz").unwrap();
+            let (mut r1_out, mut r2_out) = (FailingWriter, FailingWriter);
+            let result = parse_batches(
+                r1, r2, &mut r1_out, &mut r2_out, &config, 5, 12, 0, options, threads, true, 1,
+            );
+            assert!(result.unwrap_err().to_string().contains("no space left"));
+        }
+    }
+
+    #[test]
+    fn parse_failing_record() {
+        let config = Config::from_file(TEST_PATH, false, false).unwrap();
+        let options = OrientationOptions {
+            orientation: Some(Orientation::Forward),
+            ..Default::default()
+        };
+        // records without qualities fail on the first read
+        let fasta = || {
+            let records = (0..100).map(|i| format!(">r{}\nACGTACGT\n", i)).collect::<String>();
+            Box::new(fxread::FastaReader::new(std::io::Cursor::new(records.into_bytes()))) as Box<dyn FastxRead<Item = Record>>
+        };
+        for threads in [1, 4] {
+            let (mut r1_out, mut r2_out) = (Vec::new(), Vec::new());
+            let result = parse_batches(
+                fasta(), fasta(), &mut r1_out, &mut r2_out, &config, 5, 12, 0, options, threads, true, 1,
+            );
+            assert!(result.unwrap_err().to_string().contains("no qualities"));
+        }
+    }
+
+    #[test]
+    fn parse_multithreaded() {
+        let single = parse_example(1, BATCH_SIZE);
+        let multi = parse_example(4, 7);
+        assert!(!single.0.is_empty());
+        assert_eq!(single, multi);
+    }
+}
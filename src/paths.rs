@@ -0,0 +1,88 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Expands a leading `~` to the home directory and `$VAR` or `${VAR}`
+/// to the value of the environment variable
+pub fn expand(path: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        let home = std::env::var("HOME").context("Can't expand '~', HOME is not set")?;
+        expanded.push_str(&home);
+        rest = &rest[1..];
+    }
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        let (name, len) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => bail!("Unclosed '${{' in path {}", path),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        if name.is_empty() {
+            bail!("Empty variable name in path {}", path);
+        }
+        let value = std::env::var(name)
+            .with_context(|| format!("Environment variable {} in path {} is not set", name, path))?;
+        expanded.push_str(&value);
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Expands a path and resolves it against `base_dir` if it is relative.
+/// Returns the absolute path, without symlinks if the file exists
+pub fn resolve(path: &str, base_dir: &Path) -> Result<PathBuf> {
+    let expanded = PathBuf::from(expand(path)?);
+    let joined = if expanded.is_absolute() {
+        expanded
+    } else {
+        base_dir.join(expanded)
+    };
+    match joined.canonicalize() {
+        Ok(canonical) => Ok(canonical),
+        Err(_) => Ok(std::path::absolute(&joined)?),
+    }
+}
+
+/// Returns the directory of a file, to resolve the paths given in it
+pub fn parent_dir(path: &str) -> &Path {
+    Path::new(path).parent().unwrap_or(Path::new(""))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn expand_paths() {
+        std::env::set_var("PIPSPEAK_TEST_DIR", "/data/runs");
+        assert_eq!(expand("bc1.txt").unwrap(), "bc1.txt");
+        assert_eq!(expand("$PIPSPEAK_TEST_DIR/bc1.txt").unwrap(), "/data/runs/bc1.txt");
+        assert_eq!(expand("${PIPSPEAK_TEST_DIR}_old/bc1.txt").unwrap(), "/data/runs_old/bc1.txt");
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(expand("~/bc1.txt").unwrap(), format!("{}/bc1.txt", home));
+        assert_eq!(expand("a~/bc1.txt").unwrap(), "a~/bc1.txt");
+        assert!(expand("$PIPSPEAK_TEST_UNSET/bc1.txt").is_err());
+        assert!(expand("${PIPSPEAK_TEST_DIR/bc1.txt").is_err());
+    }
+
+    #[test]
+    fn resolve_paths() {
+        let cwd = std::env::current_dir().unwrap();
+        let resolved = resolve("fb_v3_bc1.tsv", Path::new("data/barcodes_v3")).unwrap();
+        assert_eq!(resolved, cwd.join("data/barcodes_v3/fb_v3_bc1.tsv"));
+        let resolved = resolve("../configs/missing.txt", Path::new("data")).unwrap();
+        assert!(resolved.is_absolute());
+        assert!(resolved.ends_with("configs/missing.txt"));
+        assert_eq!(resolve("/tmp/bc1.txt", Path::new("data")).unwrap(), PathBuf::from("/tmp/bc1.txt"));
+    }
+}
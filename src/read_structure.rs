@@ -0,0 +1,278 @@
+use anyhow::{bail, Context, Result};
+use std::{fmt, str::FromStr};
+
+/// The type of a read structure segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SegmentKind {
+    /// Nucleotides that are skipped (`X`)
+    Skip,
+    /// A barcode round matched against a named onlist (`B[name]`)
+    Barcode(String),
+    /// A constant sequence, comma separated variants (`S[seq]`)
+    Spacer(String),
+    /// The UMI (`U`)
+    Umi,
+}
+
+/// A segment of the barcode read with its length or length range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub min_len: usize,
+    pub max_len: usize,
+}
+
+impl FromStr for Segment {
+    type Err = anyhow::Error;
+
+    /// Parses a segment such as `8B[bc1]`, `4S[AACC]`, `0-3X` or `12U`
+    fn from_str(token: &str) -> Result<Self> {
+        let type_pos = token
+            .find(|c: char| c.is_ascii_alphabetic())
+            .with_context(|| format!("Segment '{}' has no type", token))?;
+        let (lengths, rest) = token.split_at(type_pos);
+        let (min_len, max_len) = match lengths.split_once('-') {
+            Some((min, max)) => (parse_len(min, token)?, parse_len(max, token)?),
+            None => {
+                let len = parse_len(lengths, token)?;
+                (len, len)
+            }
+        };
+        if min_len > max_len {
+            bail!("Segment '{}' has a minimum length above its maximum", token);
+        }
+
+        let (symbol, argument) = match rest.split_once('[') {
+            Some((symbol, argument)) => match argument.strip_suffix(']') {
+                Some(argument) if !argument.is_empty() => (symbol, Some(argument)),
+                _ => bail!("Segment '{}' has an unclosed or empty bracket", token),
+            },
+            None => (rest, None),
+        };
+        let kind = match (symbol, argument) {
+            ("X", None) => SegmentKind::Skip,
+            ("U", None) => SegmentKind::Umi,
+            ("B", Some(name)) => SegmentKind::Barcode(name.to_string()),
+            ("S", Some(seq)) => SegmentKind::Spacer(seq.to_ascii_uppercase()),
+            ("B", None) => bail!("Barcode segment '{}' needs an onlist name, e.g. 8B[bc1]", token),
+            ("S", None) => bail!("Spacer segment '{}' needs a sequence, e.g. 4S[AACC]", token),
+            _ => bail!("Unknown segment '{}', expected X, B[name], S[seq] or U", token),
+        };
+
+        match &kind {
+            SegmentKind::Spacer(seq) => {
+                if min_len != max_len {
+                    bail!("Spacer '{}' must have a fixed length", token);
+                }
+                if seq.split(',').any(|variant| variant.len() != min_len) {
+                    bail!("Spacer '{}' doesn't have a length of {}", token, min_len);
+                }
+            }
+            SegmentKind::Umi if min_len != max_len || min_len == 0 => {
+                bail!("UMI '{}' must have a fixed length above 0", token);
+            }
+            SegmentKind::Barcode(_) if min_len == 0 => {
+                bail!("Barcode '{}' must have a length above 0", token);
+            }
+            _ => {}
+        }
+        Ok(Self { kind, min_len, max_len })
+    }
+}
+
+fn parse_len(len: &str, token: &str) -> Result<usize> {
+    len.parse()
+        .with_context(|| format!("Invalid length '{}' in segment '{}'", len, token))
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.min_len == self.max_len {
+            write!(f, "{}", self.min_len)?;
+        } else {
+            write!(f, "{}-{}", self.min_len, self.max_len)?;
+        }
+        match &self.kind {
+            SegmentKind::Skip => write!(f, "X"),
+            SegmentKind::Barcode(name) => write!(f, "B[{}]", name),
+            SegmentKind::Spacer(seq) => write!(f, "S[{}]", seq),
+            SegmentKind::Umi => write!(f, "U"),
+        }
+    }
+}
+
+/// A barcode round described by a read structure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Round {
+    /// Name of the onlist of the round
+    pub name: String,
+    pub min_len: usize,
+    pub max_len: usize,
+    /// Minimum and maximum shift from the end of the previous segment, if skipped
+    pub shift: Option<(usize, usize)>,
+    /// Spacer following the round
+    pub spacer: Option<String>,
+}
+
+/// The barcode read described as a sequence of segments, e.g.
+/// `0-3X 8B[bc1] 4S[AACC] 8B[bc2] 4S[ACAG] 8B[bc3] 4S[CCTA] 6S[TTCGAG] 12U`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadStructure {
+    pub rounds: Vec<Round>,
+    /// Constant sequence directly preceding the UMI
+    pub umi_anchor: Option<String>,
+    pub umi_len: usize,
+    /// Nucleotides skipped between the last segment and the UMI
+    pub umi_offset: usize,
+    segments: Vec<Segment>,
+}
+
+impl FromStr for ReadStructure {
+    type Err = anyhow::Error;
+
+    fn from_str(structure: &str) -> Result<Self> {
+        let segments = structure
+            .split_whitespace()
+            .map(Segment::from_str)
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut rounds: Vec<Round> = Vec::new();
+        let mut umi_anchor = None;
+        let mut umi = None;
+        // skipped nucleotides and whether the previous segment was a barcode
+        let mut skip = None;
+        let mut after_barcode = false;
+
+        for (idx, segment) in segments.iter().enumerate() {
+            if umi.is_some() {
+                bail!("Segment '{}' follows the UMI, which must be the last segment", segment);
+            }
+            match &segment.kind {
+                SegmentKind::Skip => {
+                    if skip.is_some() {
+                        bail!("Consecutive skipped segments at '{}'", segment);
+                    }
+                    skip = Some((segment.min_len, segment.max_len));
+                }
+                SegmentKind::Barcode(name) => {
+                    if let Some(previous) = rounds.last() {
+                        if previous.spacer.is_none() {
+                            bail!("bc{} must be followed by a spacer, only the last round may have none", rounds.len());
+                        }
+                    }
+                    if rounds.iter().any(|round| &round.name == name) {
+                        bail!("Barcode '{}' appears more than once", name);
+                    }
+                    rounds.push(Round {
+                        name: name.clone(),
+                        min_len: segment.min_len,
+                        max_len: segment.max_len,
+                        shift: skip.take(),
+                        spacer: None,
+                    });
+                }
+                SegmentKind::Spacer(seq) => {
+                    let next_is_umi = segments
+                        .get(idx + 1)
+                        .is_some_and(|next| next.kind == SegmentKind::Umi);
+                    match rounds.last_mut() {
+                        Some(round) if after_barcode && skip.is_none() => round.spacer = Some(seq.clone()),
+                        _ if next_is_umi && skip.is_none() => umi_anchor = Some(seq.clone()),
+                        _ => bail!(
+                            "Spacer '{}' must directly follow a barcode or directly precede the UMI",
+                            segment
+                        ),
+                    }
+                }
+                SegmentKind::Umi => {
+                    let offset = match skip.take() {
+                        Some((min, max)) if min == max => min,
+                        Some(_) => bail!("The segment skipped before the UMI must have a fixed length"),
+                        None => 0,
+                    };
+                    umi = Some((segment.min_len, offset));
+                }
+            }
+            after_barcode = matches!(segment.kind, SegmentKind::Barcode(_));
+        }
+
+        if rounds.is_empty() {
+            bail!("The read structure has no barcode segment");
+        }
+        let Some((umi_len, umi_offset)) = umi else {
+            bail!("The read structure has no UMI segment");
+        };
+        Ok(Self {
+            rounds,
+            umi_anchor,
+            umi_len,
+            umi_offset,
+            segments,
+        })
+    }
+}
+
+impl fmt::Display for ReadStructure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let segments = self.segments.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", segments.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    const VBCF: &str = "0-3X 8B[bc1] 4S[AACC] 8B[bc2] 4S[ACAG] 8B[bc3] 4S[CCTA] 6S[TTCGAG] 12U";
+
+    #[test]
+    fn parse_segments() {
+        let segment = "0-3X".parse::<Segment>().unwrap();
+        assert_eq!((segment.kind, segment.min_len, segment.max_len), (SegmentKind::Skip, 0, 3));
+        let segment = "8B[bc1]".parse::<Segment>().unwrap();
+        assert_eq!(segment.kind, SegmentKind::Barcode("bc1".to_string()));
+        assert_eq!("3S[atg]".parse::<Segment>().unwrap().to_string(), "3S[ATG]");
+        assert!("4S[ATG]".parse::<Segment>().is_err());
+        assert!("8B".parse::<Segment>().is_err());
+        assert!("8Q".parse::<Segment>().is_err());
+        assert!("3-1X".parse::<Segment>().is_err());
+        assert!("12-14U".parse::<Segment>().is_err());
+    }
+
+    #[test]
+    fn parse_structure() {
+        let structure = VBCF.parse::<ReadStructure>().unwrap();
+        assert_eq!(structure.rounds.len(), 3);
+        assert_eq!(structure.rounds[0].shift, Some((0, 3)));
+        assert_eq!(structure.rounds[1].shift, None);
+        assert_eq!(structure.rounds[2].spacer.as_deref(), Some("CCTA"));
+        assert_eq!(structure.umi_anchor.as_deref(), Some("TTCGAG"));
+        assert_eq!((structure.umi_len, structure.umi_offset), (12, 0));
+        assert_eq!(structure.to_string(), VBCF);
+    }
+
+    #[test]
+    fn parse_structure_v3() {
+        let structure = "8B[bc1] 3S[ATG] 6B[bc2] 3S[GAG] 6B[bc3] 5S[TCGAG] 8B[bc4] 2X 12U"
+            .parse::<ReadStructure>()
+            .unwrap();
+        assert_eq!(structure.rounds.len(), 4);
+        assert_eq!(structure.rounds[3].spacer, None);
+        assert_eq!(structure.umi_anchor, None);
+        assert_eq!((structure.umi_len, structure.umi_offset), (12, 2));
+    }
+
+    #[test]
+    fn parse_structure_invalid() {
+        // missing UMI or barcodes
+        assert!("8B[bc1] 3S[ATG]".parse::<ReadStructure>().is_err());
+        assert!("12U".parse::<ReadStructure>().is_err());
+        // a round without spacer in the middle
+        assert!("8B[bc1] 8B[bc2] 12U".parse::<ReadStructure>().is_err());
+        // segments after the UMI
+        assert!("8B[bc1] 12U 3S[ATG]".parse::<ReadStructure>().is_err());
+        // a variable skip before the UMI
+        assert!("8B[bc1] 0-2X 12U".parse::<ReadStructure>().is_err());
+        assert!("8B[bc1] 3S[ATG] 8B[bc1] 12U".parse::<ReadStructure>().is_err());
+    }
+}
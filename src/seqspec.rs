@@ -0,0 +1,671 @@
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use chrono::Local;
+use serde_yaml::{
+    value::{Tag, TaggedValue},
+    Value,
+};
+use std::path::Path;
+
+use crate::chemistry;
+use crate::paths;
+use crate::read_structure::Round;
+
+/// Region types that can be part of the barcode read
+const SUPPORTED_REGION_TYPES: [&str; 5] = ["barcode", "linker", "umi", "cdna", "gdna"];
+
+/// Version of the seqspec format written by pipspeak
+const SEQSPEC_VERSION: &str = "0.3.0";
+
+/// TruSeq Read 1 primer in front of the barcode read
+const READ1_PRIMER: &str = "ACACTCTTTCCCTACACGACGCTCTTCCGATCT";
+
+/// Nextera mosaic end in front of the cDNA read
+const READ2_PRIMER: &str = "AGATGTGTATAAGAGACAG";
+
+/// Longest cDNA described in exported seqspecs
+const CDNA_MAX_LEN: usize = 150;
+
+/// A seqspec assay
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Assay {
+    #[serde(default)]
+    pub seqspec_version: String,
+    pub assay_id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub doi: String,
+    #[serde(default)]
+    pub date: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub modalities: Vec<String>,
+    #[serde(default)]
+    pub lib_struct: String,
+    #[serde(default)]
+    pub library_protocol: Value,
+    #[serde(default)]
+    pub library_kit: Value,
+    #[serde(default)]
+    pub sequence_protocol: Value,
+    #[serde(default)]
+    pub sequence_kit: Value,
+    pub sequence_spec: Vec<Read>,
+    pub library_spec: Vec<Region>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Read {
+    pub read_id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub modality: String,
+    pub primer_id: String,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub strand: String,
+    #[serde(default)]
+    pub files: Vec<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Region {
+    pub region_id: String,
+    pub region_type: String,
+    #[serde(default)]
+    pub name: String,
+    pub sequence_type: String,
+    pub sequence: String,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub onlist: Option<Onlist>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<Region>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Onlist {
+    #[serde(default)]
+    pub file_id: String,
+    pub filename: String,
+    #[serde(default)]
+    pub filetype: String,
+    #[serde(default)]
+    pub filesize: u64,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub urltype: String,
+    #[serde(default)]
+    pub md5: String,
+    pub location: Option<String>,
+}
+
+impl Region {
+    fn new(region_id: &str, region_type: &str, name: &str, sequence_type: &str, sequence: String) -> Self {
+        Self {
+            region_id: region_id.to_string(),
+            region_type: region_type.to_string(),
+            name: name.to_string(),
+            sequence_type: sequence_type.to_string(),
+            min_len: sequence.len(),
+            max_len: sequence.len(),
+            sequence,
+            onlist: None,
+            regions: Vec::new(),
+        }
+    }
+
+    /// A constant sequence
+    fn fixed(region_id: &str, region_type: &str, name: &str, sequence: &str) -> Self {
+        Self::new(region_id, region_type, name, "fixed", sequence.to_string())
+    }
+
+    /// A sequence of `min_len` to `max_len` arbitrary nucleotides
+    fn random(region_id: &str, region_type: &str, name: &str, min_len: usize, max_len: usize) -> Self {
+        Self {
+            min_len,
+            ..Self::new(region_id, region_type, name, "random", "N".repeat(max_len))
+        }
+    }
+
+    /// A sequence of `min_len` to `max_len` nucleotides listed in an onlist file.
+    /// The onlist is referred to by its absolute path if it exists
+    fn onlist(region_id: &str, region_type: &str, name: &str, min_len: usize, max_len: usize, path: &str) -> Self {
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        let path = path.as_path();
+        Self {
+            min_len,
+            onlist: Some(Onlist {
+                file_id: path.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default(),
+                filename: path.to_string_lossy().into_owned(),
+                filetype: path.extension().map(|e| e.to_string_lossy().into_owned()).unwrap_or_default(),
+                filesize: std::fs::metadata(path).map_or(0, |m| m.len()),
+                url: String::new(),
+                urltype: "local".to_string(),
+                md5: String::new(),
+                location: Some("local".to_string()),
+            }),
+            ..Self::new(region_id, region_type, name, "onlist", "N".repeat(max_len))
+        }
+    }
+}
+
+/// A stagger segment described by a seqspec region
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaggerRegion {
+    /// Region id of the segment
+    pub name: String,
+    pub start: usize,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub onlist: Option<String>,
+}
+
+/// The barcode read construct of a seqspec
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Construct {
+    pub rounds: Vec<Round>,
+    pub stagger: Option<StaggerRegion>,
+    pub umi_anchor: Option<String>,
+    pub umi_len: usize,
+    pub umi_offset: usize,
+    /// Onlist path of each barcode round by its region id
+    pub onlists: IndexMap<String, String>,
+}
+
+impl Construct {
+    /// Loads the construct of a seqspec, with onlists relative to the seqspec
+    pub fn from_file(path: &str) -> Result<Self> {
+        let assay = Assay::from_file(path).with_context(|| format!("Failed to load the seqspec {}", path))?;
+        info!("Construct of seqspec assay {} from {}", assay.assay_id, path);
+        assay.construct(paths::parent_dir(path))
+    }
+}
+
+impl Construct {
+    /// Describes the barcode read as seqspec regions, from the read 1 primer to the UMI
+    fn input_regions(&self) -> Vec<Region> {
+        let mut regions = vec![Region::fixed("read1_primer", "custom_primer", "Read 1 primer", READ1_PRIMER)];
+        if let Some(stagger) = &self.stagger {
+            if stagger.start > 0 {
+                regions.push(Region::random("stagger_offset", "linker", "Offset of the stagger", stagger.start, stagger.start));
+            }
+            regions.push(match &stagger.onlist {
+                Some(path) => Region::onlist(&stagger.name, "linker", "Stagger", stagger.min_len, stagger.max_len, path),
+                None => Region::random(&stagger.name, "linker", "Stagger", stagger.min_len, stagger.max_len),
+            });
+        }
+        for (idx, round) in self.rounds.iter().enumerate() {
+            if let Some((min_shift, max_shift)) = round.shift {
+                let name = format!("Shift of {}", round.name);
+                regions.push(Region::random(&format!("{}_shift", round.name), "linker", &name, min_shift, max_shift));
+            }
+            regions.push(self.barcode_region(round));
+            if let Some(spacer) = &round.spacer {
+                regions.push(Self::spacer_region(idx, spacer));
+            }
+        }
+        if self.umi_offset > 0 {
+            regions.push(Region::random("umi_offset", "linker", "Offset of the UMI", self.umi_offset, self.umi_offset));
+        }
+        if let Some(anchor) = &self.umi_anchor {
+            regions.push(Region::fixed("umi_anchor", "linker", "UMI anchor", anchor));
+        }
+        regions.push(self.umi_region());
+        regions
+    }
+
+    /// Describes the barcode read written by pipspeak: the barcodes, with their
+    /// spacers if `linkers` is set, followed by the UMI
+    fn output_regions(&self, linkers: bool) -> Vec<Region> {
+        let mut regions = vec![Region::fixed("read1_primer", "custom_primer", "Read 1 primer", READ1_PRIMER)];
+        for (idx, round) in self.rounds.iter().enumerate() {
+            regions.push(self.barcode_region(round));
+            if let Some(spacer) = round.spacer.as_ref().filter(|_| linkers) {
+                regions.push(Self::spacer_region(idx, spacer));
+            }
+        }
+        regions.push(self.umi_region());
+        regions
+    }
+
+    fn barcode_region(&self, round: &Round) -> Region {
+        let name = format!("Barcode {}", round.name);
+        match self.onlists.get(&round.name) {
+            Some(path) => Region::onlist(&round.name, "barcode", &name, round.min_len, round.max_len, path),
+            None => Region::random(&round.name, "barcode", &name, round.min_len, round.max_len),
+        }
+    }
+
+    fn spacer_region(idx: usize, spacer: &str) -> Region {
+        let region_id = format!("spacer_{}", idx + 1);
+        Region::fixed(&region_id, "linker", &format!("Spacer {}", idx + 1), spacer)
+    }
+
+    fn umi_region(&self) -> Region {
+        Region::random("umi", "umi", "UMI", self.umi_len, self.umi_len)
+    }
+
+    /// Returns the seqspec of the reads read by pipspeak
+    pub fn input_assay(&self) -> Assay {
+        Assay::new(
+            "pipspeak_input",
+            "Barcode read and cDNA read as parsed by pipspeak",
+            self.input_regions(),
+        )
+    }
+
+    /// Returns the seqspec of the 10X-style reads written by pipspeak
+    pub fn output_assay(&self, linkers: bool) -> Assay {
+        Assay::new(
+            "pipspeak_output",
+            "10X-style barcode and UMI read and cDNA read written by pipspeak",
+            self.output_regions(linkers),
+        )
+    }
+}
+
+impl Assay {
+    /// An RNA assay of a barcode read over `regions` (starting with its primer) and a cDNA read
+    fn new(assay_id: &str, description: &str, mut regions: Vec<Region>) -> Self {
+        // the barcode read covers everything after its primer
+        let (min_len, max_len) = regions[1..]
+            .iter()
+            .fold((0, 0), |(min, max), region| (min + region.min_len, max + region.max_len));
+        regions.push(Region {
+            sequence: "X".to_string(),
+            ..Region::random("cdna", "cdna", "cDNA", 1, CDNA_MAX_LEN)
+        });
+        regions.push(Region::fixed("read2_primer", "custom_primer", "Read 2 primer", READ2_PRIMER));
+        let (library_min, library_max) = regions
+            .iter()
+            .fold((0, 0), |(min, max), region| (min + region.min_len, max + region.max_len));
+        let library = Region {
+            regions,
+            min_len: library_min,
+            max_len: library_max,
+            ..Region::new("rna", "rna", "RNA", "joined", String::new())
+        };
+        let library = Region {
+            sequence: library.regions.iter().map(|region| region.sequence.as_str()).collect(),
+            ..library
+        };
+        let read = |read_id: &str, name: &str, primer_id: &str, min_len, max_len, strand: &str| Read {
+            read_id: read_id.to_string(),
+            name: name.to_string(),
+            modality: "rna".to_string(),
+            primer_id: primer_id.to_string(),
+            min_len,
+            max_len,
+            strand: strand.to_string(),
+            files: Vec::new(),
+        };
+        Self {
+            seqspec_version: SEQSPEC_VERSION.to_string(),
+            assay_id: assay_id.to_string(),
+            name: assay_id.to_string(),
+            doi: String::new(),
+            date: Local::now().format("%d %B %Y").to_string(),
+            description: description.to_string(),
+            modalities: vec!["rna".to_string()],
+            lib_struct: String::new(),
+            library_protocol: Value::from("custom"),
+            library_kit: Value::from("custom"),
+            sequence_protocol: Value::from("Illumina"),
+            sequence_kit: Value::from("custom"),
+            sequence_spec: vec![
+                read("R1.fastq.gz", "Read 1 (barcodes + UMI)", "read1_primer", min_len, max_len, "pos"),
+                read("R2.fastq.gz", "Read 2 (cDNA)", "read2_primer", 1, CDNA_MAX_LEN, "neg"),
+            ],
+            library_spec: vec![library],
+        }
+    }
+
+    /// Writes the assay as a tagged seqspec yaml
+    pub fn to_file(&self, path: &str) -> Result<()> {
+        let yaml = serde_yaml::to_string(&add_tags(serde_yaml::to_value(self)?))?;
+        std::fs::write(path, yaml)?;
+        Ok(())
+    }
+}
+
+fn tagged(tag: &str, value: Value) -> Value {
+    Value::Tagged(Box::new(TaggedValue {
+        tag: Tag::new(tag),
+        value,
+    }))
+}
+
+/// Adds the `!Assay`, `!Read`, `!Region` and `!Onlist` tags of a seqspec document
+fn add_tags(value: Value) -> Value {
+    fn tag_region(region: Value) -> Value {
+        let Value::Mapping(mut map) = region else {
+            return region;
+        };
+        if let Some(Value::Sequence(regions)) = map.get_mut("regions") {
+            *regions = regions.drain(..).map(tag_region).collect();
+        }
+        if let Some(onlist) = map.get_mut("onlist").filter(|onlist| !onlist.is_null()) {
+            *onlist = tagged("Onlist", onlist.clone());
+        }
+        tagged("Region", Value::Mapping(map))
+    }
+    let Value::Mapping(mut map) = value else {
+        return value;
+    };
+    if let Some(Value::Sequence(reads)) = map.get_mut("sequence_spec") {
+        *reads = reads.drain(..).map(|read| tagged("Read", read)).collect();
+    }
+    if let Some(Value::Sequence(regions)) = map.get_mut("library_spec") {
+        *regions = regions.drain(..).map(tag_region).collect();
+    }
+    tagged("Assay", Value::Mapping(map))
+}
+
+/// Removes the `!Assay`, `!Region`, ... tags of a seqspec document
+fn strip_tags(value: Value) -> Value {
+    match value {
+        Value::Tagged(tagged) => strip_tags(tagged.value),
+        Value::Sequence(seq) => Value::Sequence(seq.into_iter().map(strip_tags).collect()),
+        Value::Mapping(map) => Value::Mapping(map.into_iter().map(|(k, v)| (k, strip_tags(v))).collect()),
+        value => value,
+    }
+}
+
+impl Assay {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let value = serde_yaml::from_str::<Value>(&contents)?;
+        Ok(serde_yaml::from_value(strip_tags(value))?)
+    }
+
+    /// Returns the read carrying the barcodes, the first read on the positive strand
+    pub fn barcode_read(&self) -> Result<&Read> {
+        self.sequence_spec
+            .iter()
+            .find(|read| read.strand == "pos")
+            .context("The seqspec has no read on the positive strand")
+    }
+
+    /// Returns the innermost regions in library order
+    pub fn leaves(&self) -> Vec<&Region> {
+        fn collect<'a>(regions: &'a [Region], leaves: &mut Vec<&'a Region>) {
+            for region in regions {
+                if region.regions.is_empty() {
+                    leaves.push(region);
+                } else {
+                    collect(&region.regions, leaves);
+                }
+            }
+        }
+        let mut leaves = Vec::new();
+        collect(&self.library_spec, &mut leaves);
+        leaves
+    }
+
+    /// Describes the barcode read as barcode rounds, spacers, stagger and UMI.
+    /// Onlist files are relative to `dir`
+    pub fn construct(&self, dir: &Path) -> Result<Construct> {
+        let read = self.barcode_read()?;
+        let leaves = self.leaves();
+        // the read starts after its primer, or at the start of the library
+        let first = leaves
+            .iter()
+            .position(|region| region.region_id == read.primer_id)
+            .map_or(0, |idx| idx + 1);
+
+        let mut rounds: Vec<Round> = Vec::new();
+        let mut onlists = IndexMap::new();
+        let mut stagger = None;
+        let mut umi_anchor = None;
+        let mut umi = None;
+        // nucleotides of the read before the current region
+        let mut position = (0, 0);
+        // skipped nucleotides since the last used region
+        let mut skip = (0, 0);
+        let mut after_barcode = false;
+
+        let regions = &leaves[first..];
+        for (idx, region) in regions.iter().enumerate() {
+            if !SUPPORTED_REGION_TYPES.contains(&region.region_type.as_str()) {
+                bail!(
+                    "Region '{}' has the unsupported type '{}', expected one of {}",
+                    region.region_id,
+                    region.region_type,
+                    SUPPORTED_REGION_TYPES.join(", ")
+                );
+            }
+            let fixed = region.min_len == region.max_len;
+            match region.region_type.as_str() {
+                "barcode" => {
+                    if region.sequence_type != "onlist" {
+                        bail!("Barcode region '{}' has no onlist", region.region_id);
+                    }
+                    if let Some(previous) = rounds.last() {
+                        if previous.spacer.is_none() {
+                            bail!("Barcode region '{}' must be followed by a fixed linker", previous.name);
+                        }
+                    }
+                    onlists.insert(region.region_id.clone(), onlist_path(region, dir)?);
+                    rounds.push(Round {
+                        name: region.region_id.clone(),
+                        min_len: region.min_len,
+                        max_len: region.max_len,
+                        shift: (skip != (0, 0)).then_some(skip),
+                        spacer: None,
+                    });
+                    skip = (0, 0);
+                }
+                "linker" if region.sequence_type == "onlist" => {
+                    if !rounds.is_empty() || stagger.is_some() || skip.0 != skip.1 {
+                        bail!(
+                            "Linker '{}' with an onlist is only supported once in front of the first barcode",
+                            region.region_id
+                        );
+                    }
+                    stagger = Some(StaggerRegion {
+                        name: region.region_id.clone(),
+                        start: skip.0,
+                        min_len: region.min_len,
+                        max_len: region.max_len,
+                        onlist: Some(onlist_path(region, dir)?),
+                    });
+                    skip = (0, 0);
+                }
+                "linker" if region.sequence_type == "fixed" && fixed && skip == (0, 0) && after_barcode => {
+                    rounds.last_mut().unwrap().spacer = Some(region.sequence.clone());
+                }
+                "linker"
+                    if region.sequence_type == "fixed"
+                        && fixed
+                        && skip == (0, 0)
+                        && regions.get(idx + 1).is_some_and(|next| next.region_type == "umi") =>
+                {
+                    umi_anchor = Some(region.sequence.clone());
+                }
+                "linker" => {
+                    skip = (skip.0 + region.min_len, skip.1 + region.max_len);
+                }
+                "umi" => {
+                    if !fixed {
+                        bail!("UMI region '{}' must have a fixed length", region.region_id);
+                    }
+                    if skip.0 != skip.1 {
+                        bail!("The regions between the last barcode and the UMI must have a fixed length");
+                    }
+                    umi = Some((region.min_len, skip.0));
+                }
+                _ => bail!("Region '{}' of type {} precedes the UMI", region.region_id, region.region_type),
+            }
+            position = (position.0 + region.min_len, position.1 + region.max_len);
+            after_barcode = region.region_type == "barcode";
+            if umi.is_some() {
+                break;
+            }
+        }
+
+        if rounds.is_empty() {
+            bail!("The seqspec has no barcode region in {}", read.read_id);
+        }
+        let Some((umi_len, umi_offset)) = umi else {
+            bail!("The seqspec has no UMI region in {}", read.read_id);
+        };
+        if position.1 > read.max_len {
+            bail!(
+                "{} of up to {} nt doesn't cover the barcodes and UMI ({} nt)",
+                read.read_id,
+                read.max_len,
+                position.1
+            );
+        }
+        if position.1 > read.min_len {
+            warn!(
+                "Reads of {} shorter than {} nt may not cover the barcodes and UMI",
+                read.read_id, position.1
+            );
+        }
+        Ok(Construct {
+            rounds,
+            stagger,
+            umi_anchor,
+            umi_len,
+            umi_offset,
+            onlists,
+        })
+    }
+}
+
+/// Returns the path of a local onlist relative to the seqspec
+fn onlist_path(region: &Region, dir: &Path) -> Result<String> {
+    let onlist = region
+        .onlist
+        .as_ref()
+        .with_context(|| format!("Region '{}' has no onlist", region.region_id))?;
+    if onlist.location.as_deref().is_some_and(|location| location != "local") {
+        bail!(
+            "The onlist of region '{}' is not local, give it with --onlist {}=PATH",
+            region.region_id,
+            region.region_id
+        );
+    }
+    if chemistry::is_builtin(&onlist.filename) {
+        return Ok(onlist.filename.clone());
+    }
+    Ok(paths::resolve(&onlist.filename, dir)?.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    const VBCF_SEQSPEC: &str = "configs/20260521/vbcf_seqspec_20260521.yaml";
+
+    #[test]
+    fn load_assay() {
+        let assay = Assay::from_file(VBCF_SEQSPEC).unwrap();
+        assert_eq!(assay.assay_id, "custom_split_seq");
+        assert_eq!(assay.sequence_spec.len(), 2);
+        assert_eq!(assay.barcode_read().unwrap().read_id, "R1.fastq.gz");
+        assert_eq!(assay.leaves().len(), 11);
+    }
+
+    #[test]
+    fn vbcf_construct() {
+        let assay = Assay::from_file(VBCF_SEQSPEC).unwrap();
+        let construct = assay.construct(Path::new("configs/20260521")).unwrap();
+        let dir = std::env::current_dir().unwrap().join("configs/20260521");
+        let names = construct.rounds.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["barcode1", "barcode2", "barcode3"]);
+        let spacers = construct.rounds.iter().map(|r| r.spacer.as_deref()).collect::<Vec<_>>();
+        assert_eq!(spacers, vec![Some("AACC"), Some("ACAG"), Some("CCTA")]);
+        assert_eq!(
+            construct.stagger,
+            Some(StaggerRegion {
+                name: "phase_block".to_string(),
+                start: 4,
+                min_len: 1,
+                max_len: 3,
+                onlist: Some(dir.join("phase_block_onlist.txt").to_string_lossy().into_owned()),
+            })
+        );
+        assert_eq!(construct.umi_anchor.as_deref(), Some("TTCGAG"));
+        assert_eq!((construct.umi_len, construct.umi_offset), (14, 0));
+        assert_eq!(construct.onlists["barcode2"], dir.join("bc2_onlist.txt").to_string_lossy());
+    }
+
+    #[test]
+    fn unsupported_region() {
+        let contents = std::fs::read_to_string(VBCF_SEQSPEC)
+            .unwrap()
+            .replace("region_type: umi", "region_type: index5");
+        let value = serde_yaml::from_str::<Value>(&contents).unwrap();
+        let assay = serde_yaml::from_value::<Assay>(strip_tags(value)).unwrap();
+        let error = assay.construct(Path::new(".")).unwrap_err();
+        assert!(error.to_string().contains("unsupported type 'index5'"));
+    }
+
+    #[test]
+    fn export_roundtrip() {
+        let construct = Construct {
+            rounds: vec![
+                Round {
+                    name: "bc1".to_string(),
+                    min_len: 8,
+                    max_len: 8,
+                    shift: None,
+                    spacer: Some("AACC".to_string()),
+                },
+                Round {
+                    name: "bc2".to_string(),
+                    min_len: 8,
+                    max_len: 8,
+                    shift: Some((0, 2)),
+                    spacer: Some("CCTA".to_string()),
+                },
+            ],
+            stagger: Some(StaggerRegion {
+                name: "stagger".to_string(),
+                start: 4,
+                min_len: 1,
+                max_len: 3,
+                onlist: Some("/onlists/phase.txt".to_string()),
+            }),
+            umi_anchor: Some("TTCGAG".to_string()),
+            umi_len: 12,
+            umi_offset: 0,
+            onlists: [("bc1", "/onlists/bc1.txt"), ("bc2", "/onlists/bc2.txt")]
+                .into_iter()
+                .map(|(name, path)| (name.to_string(), path.to_string()))
+                .collect(),
+        };
+        let yaml = serde_yaml::to_string(&add_tags(serde_yaml::to_value(construct.input_assay()).unwrap())).unwrap();
+        assert!(yaml.starts_with("!Assay"));
+        assert!(yaml.contains("!Region"));
+        assert!(yaml.contains("!Onlist"));
+        let value = serde_yaml::from_str::<Value>(&yaml).unwrap();
+        let assay = serde_yaml::from_value::<Assay>(strip_tags(value)).unwrap();
+        let read = assay.barcode_read().unwrap();
+        assert_eq!((read.min_len, read.max_len), (4 + 1 + 8 + 4 + 8 + 4 + 6 + 12, 4 + 3 + 8 + 4 + 2 + 8 + 4 + 6 + 12));
+        let mut imported = assay.construct(Path::new("")).unwrap();
+        // stagger starts are linkers, the name of the stagger region is kept
+        imported.stagger.as_mut().unwrap().name = "stagger".to_string();
+        assert_eq!(imported, construct);
+
+        let output = construct.output_assay(true);
+        let regions = output.library_spec[0].regions.iter().map(|r| r.region_id.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            regions,
+            vec!["read1_primer", "bc1", "spacer_1", "bc2", "spacer_2", "umi", "cdna", "read2_primer"]
+        );
+        let read = output.barcode_read().unwrap();
+        assert_eq!((read.min_len, read.max_len), (36, 36));
+        let output = construct.output_assay(false);
+        assert_eq!(output.barcode_read().unwrap().max_len, 28);
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{bail, Result};
+use log::info;
+use serde::Serialize;
+use std::fmt;
+
+use crate::barcodes::Tolerance;
+use crate::cli::Cli;
+use crate::config::ConfigParameters;
+use crate::orientation::Orientation;
+
+/// Where the effective value of a parameter comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Default,
+    Config,
+    Cli,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Config => write!(f, "set in the config"),
+            Self::Cli => write!(f, "set on the command line"),
+        }
+    }
+}
+
+/// The effective value of a parameter and its source
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Setting<T> {
+    /// Takes the command line value over the config value over the default
+    pub fn resolve(default: T, config: Option<T>, cli: Option<T>) -> Self {
+        match (cli, config) {
+            (Some(value), _) => Self { value, source: Source::Cli },
+            (None, Some(value)) => Self { value, source: Source::Config },
+            (None, None) => Self { value: default, source: Source::Default },
+        }
+    }
+}
+
+/// The effective processing parameters of a run
+#[derive(Debug, Clone, Serialize)]
+pub struct Settings {
+    pub offset: Setting<usize>,
+    pub umi_len: Setting<usize>,
+    pub umi_offset: Setting<usize>,
+    pub exact: Setting<bool>,
+    pub indels: Setting<bool>,
+    pub resolve_ambiguous: Setting<Option<f64>>,
+    pub max_n: Setting<usize>,
+    pub anchored: Setting<bool>,
+    pub orientation: Setting<Option<Orientation>>,
+    pub orientation_sample: Setting<usize>,
+    pub mixed_orientation: Setting<bool>,
+    pub linkers: Setting<bool>,
+}
+
+impl Settings {
+    /// Resolves every parameter from the command line, the `parameters`
+    /// block of the config and the defaults, in this order
+    pub fn resolve(args: &Cli, config: &ConfigParameters) -> Result<Self> {
+        let settings = Self {
+            offset: Setting::resolve(5, config.offset, args.offset),
+            umi_len: Setting::resolve(12, config.umi_len, args.umi_len),
+            umi_offset: Setting::resolve(0, config.umi_offset, args.umi_offset),
+            exact: Setting::resolve(false, config.exact, args.exact),
+            indels: Setting::resolve(false, config.indels, args.indels),
+            resolve_ambiguous: Setting::resolve(None, config.resolve_ambiguous.map(Some), args.resolve_ambiguous.map(Some)),
+            max_n: Setting::resolve(0, config.max_n, args.max_n),
+            anchored: Setting::resolve(false, config.anchored, args.anchored),
+            orientation: Setting::resolve(None, config.orientation.map(Some), args.orientation.map(Some)),
+            orientation_sample: Setting::resolve(1000, config.orientation_sample, args.orientation_sample),
+            mixed_orientation: Setting::resolve(false, config.mixed_orientation, args.mixed_orientation),
+            linkers: Setting::resolve(false, config.linkers, args.linkers),
+        };
+        if settings.exact.value && settings.indels.value {
+            bail!("exact matching ({}) can't be combined with indels ({})", settings.exact.source, settings.indels.source);
+        }
+        if settings.exact.value && settings.resolve_ambiguous.value.is_some() {
+            bail!(
+                "exact matching ({}) can't be combined with resolve_ambiguous ({})",
+                settings.exact.source,
+                settings.resolve_ambiguous.source
+            );
+        }
+        Ok(settings)
+    }
+
+    /// Returns the errors corrected in barcode matching
+    pub fn tolerance(&self) -> Tolerance {
+        Tolerance {
+            min_posterior: self.resolve_ambiguous.value,
+            max_n: self.max_n.value,
+            ..Tolerance::new(self.exact.value, self.indels.value)
+        }
+    }
+
+    /// Logs the effective value and source of every parameter
+    pub fn log(&self) -> Result<()> {
+        let serde_yaml::Value::Mapping(settings) = serde_yaml::to_value(self)? else {
+            return Ok(());
+        };
+        for (name, setting) in settings {
+            let value = serde_yaml::to_string(&setting["value"])?;
+            let source = serde_yaml::to_string(&setting["source"])?;
+            info!("{}: {} ({})", name.as_str().unwrap_or_default(), value.trim(), source.trim());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::chemistry::Chemistry;
+    use crate::config::Config;
+    use crate::parser::round_window;
+    use clap::Parser;
+
+    fn args(extra: &[&str]) -> Cli {
+        let mut args = vec!["pipspeak", "-i", "r1.fq", "-I", "r2.fq", "-c", "config.yaml"];
+        args.extend(extra);
+        Cli::parse_from(args)
+    }
+
+    #[test]
+    fn resolve_precedence() {
+        let config = ConfigParameters {
+            offset: Some(3),
+            umi_len: Some(10),
+            exact: Some(true),
+            ..Default::default()
+        };
+        let settings = Settings::resolve(&args(&["--offset", "7"]), &config).unwrap();
+        assert_eq!(settings.offset, Setting { value: 7, source: Source::Cli });
+        assert_eq!(settings.umi_len, Setting { value: 10, source: Source::Config });
+        assert_eq!(settings.umi_offset, Setting { value: 0, source: Source::Default });
+        assert_eq!(settings.exact, Setting { value: true, source: Source::Config });
+        assert_eq!(settings.tolerance().mismatches, 0);
+        // flags can switch off a config value
+        let settings = Settings::resolve(&args(&["--exact=false", "--linkers"]), &config).unwrap();
+        assert_eq!(settings.exact, Setting { value: false, source: Source::Cli });
+        assert_eq!(settings.linkers, Setting { value: true, source: Source::Cli });
+        assert_eq!(settings.tolerance().mismatches, 1);
+    }
+
+    #[test]
+    fn resolve_bc1_window() {
+        let path = std::env::temp_dir().join("pipspeak_bc1_window.yaml");
+        let load = |overrides: &str| {
+            std::fs::write(&path, overrides).unwrap();
+            Config::from_preset(Chemistry::PipseqV3, path.to_str(), &Tolerance::new(false, false), false)
+        };
+        // the max_shift of bc1 in the config is overridden by --offset
+        let config = load("barcodes: {bc1: {max_shift: 1}}\n").unwrap();
+        let settings = Settings::resolve(&args(&["--offset", "7"]), config.parameters()).unwrap();
+        assert_eq!(settings.offset, Setting { value: 7, source: Source::Cli });
+        assert_eq!(round_window(&config, 0, settings.offset.value), (0, 7));
+        let settings = Settings::resolve(&args(&[]), config.parameters()).unwrap();
+        assert_eq!(settings.offset, Setting { value: 1, source: Source::Config });
+        assert_eq!(round_window(&config, 0, settings.offset.value), (0, 1));
+        // the preset sets an offset of 5, the window of bc1 is more specific
+        assert_eq!(config.parameters().offset, Some(1));
+    }
+
+    #[test]
+    fn resolve_conflicts() {
+        let config = ConfigParameters { exact: Some(true), ..Default::default() };
+        assert!(Settings::resolve(&args(&["--indels"]), &config).is_err());
+        assert!(Settings::resolve(&args(&["--resolve-ambiguous", "0.9"]), &config).is_err());
+        assert!(Settings::resolve(&args(&["--indels", "--exact=false"]), &config).is_ok());
+    }
+}
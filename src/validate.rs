@@ -0,0 +1,337 @@
+use anyhow::Result;
+use hashbrown::{HashMap, HashSet};
+use std::{fmt, io::BufRead};
+
+use crate::barcodes::{hamming, Barcodes, Spacer, Tolerance};
+use crate::config::{ConfigYaml, RoundSpec};
+use crate::whitelist::{self, Entry};
+
+/// Largest number of same length entries compared pairwise, larger
+/// whitelists are only checked for entries one substitution apart
+const MAX_PAIRWISE: usize = 5000;
+
+/// Number of line numbers listed per problem
+const MAX_LISTED: usize = 5;
+
+/// The smallest hamming distance between two entries of a round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinDistance {
+    Exact(usize),
+    /// Only a lower bound is known for large whitelists
+    AtLeast(usize),
+}
+
+impl fmt::Display for MinDistance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Exact(dist) => write!(f, "{}", dist),
+            Self::AtLeast(dist) => write!(f, ">= {}", dist),
+        }
+    }
+}
+
+/// The checks of a single barcode round
+#[derive(Debug)]
+pub struct RoundReport {
+    pub name: String,
+    pub path: String,
+    /// Distinct valid entries
+    pub entries: usize,
+    /// Smallest hamming distance between entries of the same length
+    pub min_distance: Option<MinDistance>,
+    /// Neighbours within the mismatch budget shared by different entries,
+    /// which are not corrected
+    pub ambiguous: Option<usize>,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// The checks of all rounds of a config
+#[derive(Debug)]
+pub struct Report {
+    pub rounds: Vec<RoundReport>,
+}
+
+impl Report {
+    pub fn num_errors(&self) -> usize {
+        self.rounds.iter().map(|round| round.errors.len()).sum()
+    }
+
+    pub fn num_warnings(&self) -> usize {
+        self.rounds.iter().map(|round| round.warnings.len()).sum()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for round in &self.rounds {
+            writeln!(f, "{}: {}", round.name, round.path)?;
+            writeln!(f, "  entries: {}", round.entries)?;
+            match round.min_distance {
+                Some(dist) => writeln!(f, "  min hamming distance: {}", dist)?,
+                None => writeln!(f, "  min hamming distance: -")?,
+            }
+            match round.ambiguous {
+                Some(ambiguous) => writeln!(f, "  ambiguous neighbours dropped: {}", ambiguous)?,
+                None => writeln!(f, "  ambiguous neighbours dropped: -")?,
+            }
+            for error in &round.errors {
+                writeln!(f, "  error: {}", error)?;
+            }
+            for warning in &round.warnings {
+                writeln!(f, "  warning: {}", warning)?;
+            }
+        }
+        writeln!(f, "{} errors, {} warnings", self.num_errors(), self.num_warnings())
+    }
+}
+
+/// Lists the first line numbers of a problem
+fn list_lines(lines: &[usize]) -> String {
+    let listed = lines.iter().take(MAX_LISTED).map(|l| l.to_string()).collect::<Vec<_>>().join(", ");
+    if lines.len() > MAX_LISTED {
+        format!("{} and {} more", listed, lines.len() - MAX_LISTED)
+    } else {
+        listed
+    }
+}
+
+/// Returns the smallest hamming distance between sequences of the same length
+fn min_distance(sequences: &[&[u8]]) -> Option<MinDistance> {
+    let mut by_len: HashMap<usize, Vec<&[u8]>> = HashMap::new();
+    for seq in sequences {
+        by_len.entry(seq.len()).or_default().push(seq);
+    }
+    by_len
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|group| {
+            if group.len() <= MAX_PAIRWISE {
+                let mut min = usize::MAX;
+                for (i, a) in group.iter().enumerate() {
+                    for b in &group[i + 1..] {
+                        min = min.min(hamming(a, b));
+                    }
+                }
+                return MinDistance::Exact(min);
+            }
+            let set = group.iter().copied().collect::<HashSet<_>>();
+            let one_apart = group.iter().any(|seq| {
+                let mut variant = seq.to_vec();
+                (0..seq.len()).any(|pos| {
+                    let found = b"ACGT".iter().filter(|&&nuc| nuc != seq[pos]).any(|&nuc| {
+                        variant[pos] = nuc;
+                        set.contains(variant.as_slice())
+                    });
+                    variant[pos] = seq[pos];
+                    found
+                })
+            });
+            if one_apart {
+                MinDistance::Exact(1)
+            } else {
+                MinDistance::AtLeast(2)
+            }
+        })
+        .min_by_key(|dist| match *dist {
+            MinDistance::Exact(dist) => (dist, 0),
+            MinDistance::AtLeast(dist) => (dist, 1),
+        })
+}
+
+/// Checks the whitelist of a round. `anchor` is the sequence locating
+/// the round in anchored mode
+pub fn validate_round(name: &str, round: &RoundSpec, anchor: &[Spacer], reader: impl BufRead) -> Result<RoundReport> {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+
+    let mut invalid = Vec::new();
+    let mut duplicates = Vec::new();
+    let mut first_lines: HashMap<String, usize> = HashMap::new();
+    let mut valid_entries: Vec<Entry> = Vec::new();
+    let whitelist = whitelist::read(reader, &round.format)?;
+    // blank lines are skipped when loading, but may hide a truncated file
+    if !whitelist.blank_lines.is_empty() {
+        warnings.push(format!("blank lines, which are skipped: {}", list_lines(&whitelist.blank_lines)));
+    }
+    for entry in whitelist.entries {
+        if !entry.seq.bytes().all(|nuc| b"ACGT".contains(&nuc)) {
+            invalid.push(entry.line);
+        } else if let Some(&first) = first_lines.get(&entry.seq) {
+            duplicates.push((entry.line, first));
+        } else {
+            first_lines.insert(entry.seq.clone(), entry.line);
+            valid_entries.push(entry);
+        }
+    }
+    if !invalid.is_empty() {
+        errors.push(format!("characters other than A, C, G, T on lines: {}", list_lines(&invalid)));
+    }
+    if !duplicates.is_empty() {
+        let listed = duplicates
+            .iter()
+            .take(MAX_LISTED)
+            .map(|(line, first)| format!("{} (of {})", line, first))
+            .collect::<Vec<_>>()
+            .join(", ");
+        errors.push(format!("{} duplicate entries, ignored on lines: {}", duplicates.len(), listed));
+    }
+
+    // entries followed by different spacer variants must stay distinct
+    if round.spacers.len() > 1 {
+        let mut keys: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut collisions = Vec::new();
+        for entry in &valid_entries {
+            for spacer in &round.spacers {
+                let key = [entry.seq.as_bytes(), spacer.seq()].concat();
+                match keys.get(&key) {
+                    Some(&other) if other != entry.line => collisions.push(entry.line),
+                    Some(_) => {}
+                    None => {
+                        keys.insert(key, entry.line);
+                    }
+                }
+            }
+        }
+        if !collisions.is_empty() {
+            errors.push(format!(
+                "entries equal to a different entry with another spacer variant on lines: {}",
+                list_lines(&collisions)
+            ));
+        }
+    }
+
+    for variant in anchor {
+        let containing = valid_entries
+            .iter()
+            .filter(|entry| entry.seq.as_bytes().windows(variant.seq().len()).any(|w| w == variant.seq()))
+            .map(|entry| entry.line)
+            .collect::<Vec<_>>();
+        if !containing.is_empty() {
+            warnings.push(format!(
+                "{} entries contain the anchor {}, which can misplace them with --anchored, on lines: {}",
+                containing.len(),
+                String::from_utf8_lossy(variant.seq()),
+                list_lines(&containing)
+            ));
+        }
+    }
+
+    let sequences = valid_entries.iter().map(|entry| entry.seq.as_bytes()).collect::<Vec<_>>();
+    let min_distance = min_distance(&sequences);
+    let mismatches = round.tolerance.mismatches;
+    if let Some(MinDistance::Exact(dist)) = min_distance {
+        if mismatches > 0 && dist <= mismatches {
+            warnings.push(format!(
+                "entries {} apart can be turned into each other within the {} mismatches corrected",
+                dist, mismatches
+            ));
+        }
+    }
+
+    // the lookup is built from the valid entries only
+    let ambiguous = match Barcodes::from_entries(&valid_entries, &round.spacers, &round.tolerance) {
+        Ok(barcodes) => Some(barcodes.num_ambiguous()),
+        Err(e) => {
+            errors.push(format!("can't be loaded: {:#}", e));
+            None
+        }
+    };
+
+    Ok(RoundReport {
+        name: name.to_string(),
+        path: round.path.clone(),
+        entries: valid_entries.len(),
+        min_distance,
+        ambiguous,
+        errors,
+        warnings,
+    })
+}
+
+/// Checks every round of a config without processing reads
+pub fn validate(yaml: &ConfigYaml, tolerance: &Tolerance) -> Result<Report> {
+    let rounds = yaml.rounds(tolerance);
+    let umi_anchor = yaml.umi_anchor();
+    let mut reports = Vec::with_capacity(rounds.len());
+    for (idx, round) in rounds.iter().enumerate() {
+        let name = format!("bc{}", idx + 1);
+        // a last round without spacer is located by the UMI anchor
+        let anchor = if round.spacers.is_empty() && idx + 1 == rounds.len() {
+            &umi_anchor
+        } else {
+            &round.spacers
+        };
+        let report = match whitelist::open(&round.path).and_then(|reader| validate_round(&name, round, anchor, reader)) {
+            Ok(report) => report,
+            Err(e) => RoundReport {
+                name,
+                path: round.path.clone(),
+                entries: 0,
+                min_distance: None,
+                ambiguous: None,
+                errors: vec![format!("can't be read: {:#}", e)],
+                warnings: Vec::new(),
+            },
+        };
+        reports.push(report);
+    }
+    Ok(Report { rounds: reports })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn round(spacers: &str, exact: bool) -> RoundSpec {
+        RoundSpec {
+            path: "test".to_string(),
+            format: Default::default(),
+            spacers: Spacer::from_str(spacers),
+            tolerance: Tolerance::new(exact, false),
+        }
+    }
+
+    #[test]
+    fn validate_v3() {
+        let yaml = ConfigYaml::from_file("data/config_v3.yaml").unwrap();
+        let report = validate(&yaml, &Tolerance::new(false, false)).unwrap();
+        assert_eq!(report.num_errors(), 0);
+        assert_eq!(report.rounds.len(), 4);
+        assert_eq!(report.rounds[0].entries, 96);
+        assert_eq!(report.rounds[0].ambiguous, Some(20));
+        assert!(report.rounds[0].min_distance.is_some());
+    }
+
+    #[test]
+    fn validate_problems() {
+        // comments are skipped
+        let buffer = "ACGTAC\n\nACGTAC\nACGNAC\n# plate 2\nACGTAA\nTTTTTT\n";
+        let report = validate_round("bc1", &round("ATG", false), &Spacer::from_str("ATG"), buffer.as_bytes()).unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.min_distance, Some(MinDistance::Exact(1)));
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.errors[0].contains("lines: 4"));
+        assert!(report.errors[1].contains("3 (of 1)"));
+        assert!(report.warnings[0].contains("blank lines, which are skipped: 2"));
+        // the entries one apart are within the mismatch budget
+        assert_eq!(report.warnings.len(), 2);
+        let report = validate_round("bc1", &round("ATG", true), &[], buffer.as_bytes()).unwrap();
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn validate_spacer_variants() {
+        // ACGTA + CATG equals ACGTAC + ATG
+        let buffer = "ACGTAC\nACGTA\nTTTTTT\n";
+        let report = validate_round("bc1", &round("ATG,CATG", true), &Spacer::from_str("TTT"), buffer.as_bytes()).unwrap();
+        assert!(report.errors.iter().any(|e| e.contains("spacer variant on lines: 2")));
+        assert!(report.warnings.iter().any(|w| w.contains("anchor TTT")));
+    }
+
+    #[test]
+    fn min_distances() {
+        assert_eq!(min_distance(&[b"AAAA", b"AATT", b"CCCCC"]), Some(MinDistance::Exact(2)));
+        assert_eq!(min_distance(&[b"AAAA", b"CCCCC"]), None);
+    }
+}
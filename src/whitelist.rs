@@ -0,0 +1,229 @@
+use anyhow::{bail, Context, Result};
+use flate2::bufread::MultiGzDecoder;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use crate::chemistry;
+
+/// Leading bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A column of a whitelist table, by its 1-based number or its header
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Column {
+    Number(usize),
+    Header(String),
+}
+
+/// The layout of a whitelist table. By default the sequence is in the
+/// first column and an optional name (e.g. a well) in the second
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Format {
+    pub sequence_column: Option<Column>,
+    pub name_column: Option<Column>,
+    /// Whether the first row is a header, implied by a column given by its header
+    pub header: Option<bool>,
+}
+
+impl Format {
+    fn has_header(&self) -> bool {
+        self.header.unwrap_or_else(|| {
+            [&self.sequence_column, &self.name_column]
+                .iter()
+                .any(|column| matches!(column, Some(Column::Header(_))))
+        })
+    }
+
+    /// Returns the 0-based index of the sequence and name column
+    fn indices(&self, header: Option<&[&str]>) -> Result<(usize, Option<usize>)> {
+        let sequence = match &self.sequence_column {
+            Some(column) => column_index(column, header)?,
+            None => 0,
+        };
+        let name = match &self.name_column {
+            Some(column) => Some(column_index(column, header)?),
+            // other columns of a chosen table aren't names
+            None if self.sequence_column.is_none() => Some(1),
+            None => None,
+        };
+        Ok((sequence, name))
+    }
+}
+
+/// An entry of a whitelist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Line of the entry in the file
+    pub line: usize,
+    pub seq: String,
+    pub name: Option<String>,
+}
+
+/// The entries of a whitelist and the blank lines skipped in it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Whitelist {
+    pub entries: Vec<Entry>,
+    pub blank_lines: Vec<usize>,
+}
+
+/// Opens a whitelist file, gzipped or not, or one compiled into the binary
+pub fn open(path: &str) -> Result<Box<dyn BufRead>> {
+    if let Some(contents) = chemistry::builtin_whitelist(path) {
+        return Ok(Box::new(contents.as_bytes()));
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Returns the 0-based index of a column
+fn column_index(column: &Column, header: Option<&[&str]>) -> Result<usize> {
+    match column {
+        Column::Number(0) => bail!("Whitelist columns are numbered from 1"),
+        Column::Number(number) => Ok(number - 1),
+        Column::Header(name) => {
+            let Some(header) = header else {
+                bail!("Column '{}' is given by its header, but the whitelist has no header", name);
+            };
+            header
+                .iter()
+                .position(|field| field == name)
+                .with_context(|| format!("No column '{}' in the header ({})", name, header.join(", ")))
+        }
+    }
+}
+
+/// Splits a row into its trimmed and unquoted fields
+fn split(line: &str, delimiter: char) -> Vec<&str> {
+    line.split(delimiter)
+        .map(|field| field.trim().trim_matches('"').trim())
+        .collect()
+}
+
+/// Reads the entries of a whitelist
+pub fn read_entries<R: BufRead>(reader: R, format: &Format) -> Result<Vec<Entry>> {
+    read(reader, format).map(|whitelist| whitelist.entries)
+}
+
+/// Reads a whitelist, skipping blank lines and `#` comments.
+/// Rows are tab separated, or comma separated if the first row has commas
+/// but no tabs
+pub fn read<R: BufRead>(reader: R, format: &Format) -> Result<Whitelist> {
+    let mut entries = Vec::new();
+    let mut blank_lines = Vec::new();
+    let mut delimiter = None;
+    let mut columns = None;
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let row = line.trim();
+        if row.is_empty() {
+            blank_lines.push(idx + 1);
+            continue;
+        }
+        if row.starts_with('#') {
+            continue;
+        }
+        let delimiter = *delimiter.get_or_insert(if row.contains(',') && !row.contains('\t') { ',' } else { '\t' });
+        let fields = split(row, delimiter);
+        let (sequence, name) = match columns {
+            Some(columns) => columns,
+            None => {
+                let header = format.has_header().then_some(fields.as_slice());
+                let indices = format.indices(header)?;
+                columns = Some(indices);
+                if header.is_some() {
+                    continue;
+                }
+                indices
+            }
+        };
+        let seq = match fields.get(sequence) {
+            Some(seq) if !seq.is_empty() => seq,
+            _ => bail!("No sequence in column {} on line {}", sequence + 1, idx + 1),
+        };
+        entries.push(Entry {
+            line: idx + 1,
+            seq: seq.to_string(),
+            name: name
+                .and_then(|name| fields.get(name))
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string()),
+        });
+    }
+    Ok(Whitelist { entries, blank_lines })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    #[test]
+    fn read_comments_and_blank_lines() {
+        let buffer = "# plate 1\nAGAAACCA\tA01\n\nGATTTCCC\n  # end\nAAGTCCAA\tA03 \n\n";
+        let whitelist = read(buffer.as_bytes(), &Format::default()).unwrap();
+        assert_eq!(whitelist.blank_lines, vec![3, 7]);
+        let entries = whitelist.entries;
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1], Entry { line: 4, seq: "GATTTCCC".to_string(), name: None });
+        assert_eq!(entries[2].name.as_deref(), Some("A03"));
+    }
+
+    #[test]
+    fn read_table() {
+        let buffer = "well,plate,sequence\nA01,1,\"AGAAACCA\"\nA02,1,GATTTCCC\n";
+        let format = Format {
+            sequence_column: Some(Column::Header("sequence".to_string())),
+            name_column: Some(Column::Header("well".to_string())),
+            header: None,
+        };
+        let entries = read_entries(buffer.as_bytes(), &format).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], Entry { line: 2, seq: "AGAAACCA".to_string(), name: Some("A01".to_string()) });
+        // by number, without names
+        let format = Format {
+            sequence_column: Some(Column::Number(3)),
+            header: Some(true),
+            ..Default::default()
+        };
+        let entries = read_entries(buffer.as_bytes(), &format).unwrap();
+        assert_eq!(entries[1].seq, "GATTTCCC");
+        assert_eq!(entries[1].name, None);
+    }
+
+    #[test]
+    fn read_table_invalid() {
+        let buffer = "well\tsequence\nA01\tAGAAACCA\nA02\n";
+        let column = |name: &str| Format {
+            sequence_column: Some(Column::Header(name.to_string())),
+            ..Default::default()
+        };
+        assert!(read_entries(buffer.as_bytes(), &column("barcode")).is_err());
+        // the last row has no sequence
+        assert!(read_entries(buffer.as_bytes(), &column("sequence")).is_err());
+        let format = Format { header: Some(false), ..column("sequence") };
+        assert!(read_entries(buffer.as_bytes(), &format).is_err());
+        let format = Format { sequence_column: Some(Column::Number(0)), ..Default::default() };
+        assert!(read_entries(buffer.as_bytes(), &format).is_err());
+    }
+
+    #[test]
+    fn open_gzipped() {
+        let path = std::env::temp_dir().join("pipspeak_whitelist.tsv.gz");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(b"AGAAACCA\tA01\nGATTTCCC\tA02\n").unwrap();
+        encoder.finish().unwrap();
+        let entries = read_entries(open(path.to_str().unwrap()).unwrap(), &Format::default()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].seq, "GATTTCCC");
+        assert!(open("builtin:pipseq-v3/bc1").is_ok());
+    }
+}